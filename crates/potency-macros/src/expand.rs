@@ -7,38 +7,48 @@ use syn::{
     parse2, FnArg, ItemFn, LitStr, Result, ReturnType, Signature, Token,
 };
 
-/// `#[durable]` or `#[durable(namespace = "...")]`.
+/// `#[durable]`, optionally with comma-separated `key = value` arguments:
+///
+/// - `namespace = "..."`
+/// - `cache_if = path::to::predicate`
 pub(crate) struct DurableAttr {
     namespace: Option<LitStr>,
+    cache_if: Option<syn::Path>,
 }
 
 impl Parse for DurableAttr {
     fn parse(input: ParseStream) -> Result<Self> {
-        if input.is_empty() {
-            return Ok(Self { namespace: None });
-        }
-        let ident: syn::Ident = input.parse()?;
-        if ident != "namespace" {
-            return Err(syn::Error::new_spanned(
-                ident,
-                "#[durable] only accepts `namespace = \"...\"` (or no argument)",
-            ));
-        }
-        let _eq: Token![=] = input.parse()?;
-        let namespace: LitStr = input.parse()?;
-        if !input.is_empty() {
-            return Err(
-                input.error("#[durable] only accepts a single `namespace = \"...\"` argument")
-            );
+        let mut attr = Self {
+            namespace: None,
+            cache_if: None,
+        };
+        while !input.is_empty() {
+            let ident: syn::Ident = input.parse()?;
+            let _eq: Token![=] = input.parse()?;
+            if ident == "namespace" && attr.namespace.is_none() {
+                attr.namespace = Some(input.parse()?);
+            } else if ident == "cache_if" && attr.cache_if.is_none() {
+                attr.cache_if = Some(input.parse()?);
+            } else {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "#[durable] only accepts `namespace = \"...\"` and `cache_if = path`, \
+                     each at most once",
+                ));
+            }
+            if !input.is_empty() {
+                let _comma: Token![,] = input.parse()?;
+            }
         }
-        Ok(Self {
-            namespace: Some(namespace),
-        })
+        Ok(attr)
     }
 }
 
 pub(crate) fn durable(attr: TokenStream2, input: TokenStream2) -> Result<TokenStream2> {
-    let DurableAttr { namespace } = parse2::<DurableAttr>(attr)?;
+    let DurableAttr {
+        namespace,
+        cache_if,
+    } = parse2::<DurableAttr>(attr)?;
     let fn_item: ItemFn = parse2::<ItemFn>(input)?;
 
     // Reject methods (anything with `self`).
@@ -75,6 +85,9 @@ pub(crate) fn durable(attr: TokenStream2, input: TokenStream2) -> Result<TokenSt
         quote! { .entry }
     };
 
+    // Optional conditional-caching predicate.
+    let cache_if = cache_if.map(|path| quote! { .cache_if(#path) });
+
     // The wrapper's signature inputs + return type (preserved from the
     // original), without the `async` keyword (we emit it explicitly).
     let wrapper_inputs = &fn_item.sig.inputs;
@@ -89,6 +102,7 @@ pub(crate) fn durable(attr: TokenStream2, input: TokenStream2) -> Result<TokenSt
     //       .namespace(<ns>)
     //       .<entry_or_entry_async>(<orig_ident>)
     //       .param(a1).param(a2)...
+    //       [.cache_if(<path>)]
     //       .run()
     //       .await
     let wrapper_ident = format_ident!("durable_{}", original_ident);
@@ -102,6 +116,7 @@ pub(crate) fn durable(attr: TokenStream2, input: TokenStream2) -> Result<TokenSt
             .namespace(#namespace_lit)
             #entry_method(#original_ident)
             #param_chain
+            #cache_if
             .run()
             .await
     };
@@ -112,10 +127,22 @@ pub(crate) fn durable(attr: TokenStream2, input: TokenStream2) -> Result<TokenSt
         }
     };
 
+    // Register the namespace for `potency::Roots::durable`.
+    let name_lit = LitStr::new(&original_ident.to_string(), original_ident.span());
+    let registration = quote! {
+        ::potency::inventory::submit! {
+            ::potency::DurableFn {
+                name: #name_lit,
+                namespace: #namespace_lit,
+            }
+        }
+    };
+
     // Re-emit the original function verbatim, then the wrapper.
     Ok(quote! {
         #fn_item
         #wrapper
+        #registration
     })
 }
 
@@ -11,6 +11,10 @@ mod expand;
 /// `#[durable]` or `#[durable(namespace = "my-namespace")]` (the `namespace`
 /// argument is optional; if omitted, the function's identifier is used).
 ///
+/// `#[durable(cache_if = path)]` passes `path` to `Builder::cache_if`; it
+/// must name a `fn(&T) -> bool` over the function's `Ok` type. Arguments are
+/// comma-separated.
+///
 /// Generates two functions:
 ///
 /// - `{name}` — emitted verbatim from the input tokens.
@@ -18,6 +22,10 @@ mod expand;
 ///   process-global `potency::Store` registered via
 ///   `potency::install_global_store`.
 ///
+/// It also registers the function and its namespace as a
+/// `potency::DurableFn`, so that `potency::Roots::durable` keeps its
+/// results through `Store::gc`.
+///
 /// The wrapper is always `async fn`. It uses `Store::entry` when the
 /// original was sync and `Store::entry_async` when the original was
 /// `async`. Visibility is mirrored verbatim from the original.
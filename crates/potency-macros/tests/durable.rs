@@ -176,3 +176,73 @@ fn macro_deeply_nested_durable_calls() {
         assert_eq!(n, 118);
     });
 }
+
+// ---------------------------------------------------------------------------
+// `cache_if`: rejected outputs are returned but not cached.
+// ---------------------------------------------------------------------------
+
+static FLAKY_CALLS: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+// The predicate takes `&O`, so it has to be `&Vec<u32>` rather than a slice.
+#[allow(clippy::ptr_arg)]
+fn is_complete(items: &Vec<u32>) -> bool {
+    !items.is_empty()
+}
+
+#[durable(namespace = "cache-if-ns", cache_if = is_complete)]
+fn flaky_list(n: u32) -> Result<Vec<u32>, StoreError> {
+    // The first two calls "fail open" with an empty list.
+    let call = FLAKY_CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    Ok(if call < 2 { vec![] } else { (0..n).collect() })
+}
+
+#[test]
+fn cache_if_predicate_controls_storage() {
+    install_shared_store();
+    smol::block_on(async {
+        assert!(durable_flaky_list(2).await.unwrap().is_empty());
+        assert!(durable_flaky_list(2).await.unwrap().is_empty());
+        assert_eq!(durable_flaky_list(2).await.unwrap(), vec![0, 1]);
+        assert_eq!(durable_flaky_list(2).await.unwrap(), vec![0, 1]);
+        assert_eq!(FLAKY_CALLS.load(std::sync::atomic::Ordering::SeqCst), 3);
+    });
+}
+
+// ---------------------------------------------------------------------------
+// Registration: `Roots::durable` keeps every `#[durable]` namespace.
+// ---------------------------------------------------------------------------
+
+#[test]
+fn durable_namespaces_are_gc_roots() {
+    let registered: Vec<_> = potency::durable_fns()
+        .map(|f| (f.name, f.namespace))
+        .collect();
+    assert!(registered.contains(&("sub", "sub")));
+    assert!(registered.contains(&("add", "sync-default-ns")));
+
+    smol::block_on(async {
+        // A store of its own, so the other tests' entries don't count.
+        let store = Store::in_memory().await.unwrap();
+        for ns in ["sync-default-ns", "renamed-long-ago"] {
+            store
+                .namespace(ns)
+                .entry(|a: u32, b: u32| Ok::<u32, StoreError>(a + b))
+                .param(1u32)
+                .param(2u32)
+                .run()
+                .await
+                .unwrap();
+        }
+        let roots = potency::Roots::new().durable();
+        let report = store.gc_dry_run(&roots).await.unwrap();
+        assert_eq!(report.total.entries, 1);
+        assert!(report.by_namespace.contains_key("renamed-long-ago"));
+        assert_eq!(store.gc(&roots).await.unwrap(), report);
+        assert_eq!(store.gc_dry_run(&roots).await.unwrap().total.entries, 0);
+        assert!(store
+            .entry_info("sync-default-ns,1,2")
+            .await
+            .unwrap()
+            .is_some());
+    });
+}
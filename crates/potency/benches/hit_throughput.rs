@@ -0,0 +1,86 @@
+//! Cache-hit throughput with and without a reader pool.
+//!
+//! Run with `cargo bench -p potency --bench hit_throughput`.
+//!
+//! Many threads hammer the same file-backed store with cache hits while one
+//! thread keeps writing. "writer only" is the single shared connection every
+//! store used before reader pools; the other rows add
+//! [`StoreOptions::readers`].
+
+use std::time::{Duration, Instant};
+
+use potency::{Store, StoreError, StoreOptions};
+
+const KEYS: u32 = 256;
+const THREADS: usize = 8;
+const RUN_FOR: Duration = Duration::from_secs(2);
+
+fn main() {
+    let dir = std::env::temp_dir().join("potency-bench-hit-throughput");
+    println!("{:<14} {:>14}", "readers", "hits/sec");
+    for readers in [0, 2, 8] {
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let hits_per_sec = run(&dir.join("bench.db"), readers);
+        let label = if readers == 0 {
+            "writer only".to_owned()
+        } else {
+            readers.to_string()
+        };
+        println!("{label:<14} {hits_per_sec:>14.0}");
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn run(path: &std::path::Path, readers: usize) -> f64 {
+    let store =
+        smol::block_on(Store::open_with(path, StoreOptions::new().readers(readers))).unwrap();
+    smol::block_on(async {
+        for k in 0..KEYS {
+            lookup(&store, k).await;
+        }
+    });
+
+    let deadline = Instant::now() + RUN_FOR;
+    // A concurrent writer, so hits have something to wait behind.
+    let writer = {
+        let store = store.clone();
+        std::thread::spawn(move || {
+            smol::block_on(async {
+                let mut k = KEYS;
+                while Instant::now() < deadline {
+                    lookup(&store, k).await;
+                    k += 1;
+                }
+            })
+        })
+    };
+    let hitters: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                smol::block_on(async {
+                    let mut hits = 0u64;
+                    while Instant::now() < deadline {
+                        lookup(&store, (hits as u32 + t as u32) % KEYS).await;
+                        hits += 1;
+                    }
+                    hits
+                })
+            })
+        })
+        .collect();
+    let hits: u64 = hitters.into_iter().map(|h| h.join().unwrap()).sum();
+    writer.join().unwrap();
+    hits as f64 / RUN_FOR.as_secs_f64()
+}
+
+async fn lookup(store: &Store, k: u32) -> u32 {
+    store
+        .namespace("bench")
+        .entry(|k: u32| Ok::<u32, StoreError>(k * 2))
+        .param(k)
+        .run()
+        .await
+        .unwrap()
+}
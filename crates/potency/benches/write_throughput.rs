@@ -0,0 +1,51 @@
+//! Miss (compute-and-store) throughput with and without write-behind.
+//!
+//! Run with `cargo bench -p potency --bench write_throughput`.
+//!
+//! Thousands of small durable calls run against a file-backed store, every
+//! one of them a miss. "per write" commits each store on its own; the other row
+//! batches them with [`StoreOptions::write_behind`].
+
+use std::time::{Duration, Instant};
+
+use potency::{Store, StoreError, StoreOptions};
+
+const CALLS: u32 = 5_000;
+
+fn main() {
+    let dir = std::env::temp_dir().join("potency-bench-write-throughput");
+    println!("{:<14} {:>14}", "writes", "misses/sec");
+    for write_behind in [None, Some(Duration::from_millis(50))] {
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut options = StoreOptions::new();
+        if let Some(interval) = write_behind {
+            options = options.write_behind(interval);
+        }
+        let misses_per_sec = run(&dir.join("bench.db"), options);
+        let label = match write_behind {
+            None => "per write".to_owned(),
+            Some(interval) => format!("batched {interval:?}"),
+        };
+        println!("{label:<14} {misses_per_sec:>14.0}");
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn run(path: &std::path::Path, options: StoreOptions) -> f64 {
+    smol::block_on(async {
+        let store = Store::open_with(path, options).await.unwrap();
+        let start = Instant::now();
+        for k in 0..CALLS {
+            store
+                .namespace("bench")
+                .entry(|k: u32| Ok::<u32, StoreError>(k * 2))
+                .param(k)
+                .run()
+                .await
+                .unwrap();
+        }
+        store.flush().await.unwrap();
+        CALLS as f64 / start.elapsed().as_secs_f64()
+    })
+}
@@ -0,0 +1,25 @@
+//! Runs a [`potency::remote::Server`], the reference remote cache.
+//!
+//! ```text
+//! potency-remote <dir> [addr]
+//! ```
+//!
+//! Entries are kept as files in `dir`. `addr` defaults to
+//! `127.0.0.1:7879`; pass `0.0.0.0:7879` to serve other machines.
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (Some(dir), addr, None) = (args.next(), args.next(), args.next()) else {
+        eprintln!("usage: potency-remote <dir> [addr]");
+        std::process::exit(2);
+    };
+    let addr = addr.unwrap_or_else(|| "127.0.0.1:7879".to_owned());
+    let result = potency::remote::Server::bind(&dir, &addr).and_then(|server| {
+        eprintln!("serving {dir} on http://{}", server.local_addr()?);
+        server.serve()
+    });
+    if let Err(e) = result {
+        eprintln!("potency-remote: {e}");
+        std::process::exit(1);
+    }
+}
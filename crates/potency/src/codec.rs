@@ -0,0 +1,260 @@
+//! How cached values are encoded and compressed in the database.
+
+use crate::{StoreError, UnknownCodecSnafu};
+
+/// The encoding of a cached value.
+///
+/// Set for the whole store with [`StoreOptions::codec`][crate::StoreOptions::codec].
+/// Every row records the codec it was written with, so a store can switch
+/// codecs without losing what it already holds, as long as the codecs of
+/// existing rows are still enabled.
+///
+/// Everything but JSON sits behind a cargo feature of the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// `serde_json`. Readable with the `sqlite3` shell. The default.
+    #[default]
+    Json,
+    /// MessagePack via `rmp-serde`. Compact, and self-describing like JSON.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// CBOR via `ciborium`. Self-describing, and keeps byte strings as
+    /// bytes.
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// `bincode`'s standard configuration. The most compact, but not
+    /// self-describing: values must be read back as the type they were
+    /// written as.
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+impl Codec {
+    /// The tag stored alongside each value.
+    pub(crate) fn tag(self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => "msgpack",
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => "cbor",
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => "bincode",
+        }
+    }
+
+    pub(crate) fn from_tag(tag: &str) -> Result<Self, StoreError> {
+        match tag {
+            "json" => Ok(Codec::Json),
+            #[cfg(feature = "msgpack")]
+            "msgpack" => Ok(Codec::MessagePack),
+            #[cfg(feature = "cbor")]
+            "cbor" => Ok(Codec::Cbor),
+            #[cfg(feature = "bincode")]
+            "bincode" => Ok(Codec::Bincode),
+            tag => UnknownCodecSnafu { tag }.fail(),
+        }
+    }
+
+    pub(crate) fn encode<T: serde::Serialize>(self, value: &T) -> Result<Vec<u8>, StoreError> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => {
+                // Structs as maps, so fields can be added or reordered.
+                rmp_serde::to_vec_named(value).map_err(|e| self.error(e))
+            }
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|e| self.error(e))?;
+                Ok(bytes)
+            }
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::serde::encode_to_vec(value, bincode::config::standard())
+                .map_err(|e| self.error(e)),
+        }
+    }
+
+    pub(crate) fn decode<T: serde::de::DeserializeOwned>(
+        self,
+        bytes: &[u8],
+    ) -> Result<T, StoreError> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| self.error(e)),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => ciborium::from_reader(bytes).map_err(|e| self.error(e)),
+            #[cfg(feature = "bincode")]
+            Codec::Bincode => bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+                .map(|(value, _)| value)
+                .map_err(|e| self.error(e)),
+        }
+    }
+
+    #[cfg(any(feature = "msgpack", feature = "cbor", feature = "bincode"))]
+    fn error(self, e: impl std::fmt::Display) -> StoreError {
+        crate::CodecSnafu {
+            codec: self,
+            message: e.to_string(),
+        }
+        .build()
+    }
+}
+
+/// How large values are compressed.
+///
+/// Values whose encoding is at least
+/// [`StoreOptions::compress_above`][crate::StoreOptions::compress_above]
+/// bytes are compressed when stored and decompressed transparently when
+/// read. Each row records how it was compressed, so changing this never
+/// makes existing rows unreadable while their algorithm stays enabled.
+///
+/// Everything but `None` sits behind a cargo feature of the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Store values as encoded. The default.
+    #[default]
+    None,
+    /// zstd at the given level (1 to 22; 3 is zstd's own default). Best
+    /// ratio for large text.
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+    /// LZ4 via `lz4_flex`. Much faster than zstd, with a lower ratio.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// The tag stored alongside a compressed value.
+    pub(crate) fn tag(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { .. } => Some("zstd"),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some("lz4"),
+        }
+    }
+
+    /// Compress `bytes`, or `None` if that wouldn't make them smaller.
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    pub(crate) fn compress(self, bytes: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        match self {
+            Compression::None => Ok(None),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => zstd::bulk::compress(bytes, level)
+                .map(|compressed| smaller(bytes, compressed))
+                .map_err(|e| compression_error("zstd", e)),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(smaller(bytes, lz4_flex::compress_prepend_size(bytes))),
+        }
+    }
+
+    /// Decompress `bytes` stored with the compression tagged `tag`.
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    pub(crate) fn decompress(tag: &str, bytes: &[u8]) -> Result<Vec<u8>, StoreError> {
+        match tag {
+            #[cfg(feature = "zstd")]
+            "zstd" => zstd::stream::decode_all(bytes).map_err(|e| compression_error(tag, e)),
+            #[cfg(feature = "lz4")]
+            "lz4" => {
+                lz4_flex::decompress_size_prepended(bytes).map_err(|e| compression_error(tag, e))
+            }
+            tag => UnknownCodecSnafu { tag }.fail(),
+        }
+    }
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn smaller(bytes: &[u8], compressed: Vec<u8>) -> Option<Vec<u8>> {
+    (compressed.len() < bytes.len()).then_some(compressed)
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn compression_error(tag: &str, e: impl std::fmt::Display) -> StoreError {
+    crate::CompressionSnafu {
+        tag,
+        message: e.to_string(),
+    }
+    .build()
+}
+
+/// Everything that decides how a value is written.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Encoding {
+    pub(crate) codec: Codec,
+    pub(crate) compression: Compression,
+    /// Encoded values shorter than this are never compressed.
+    pub(crate) compress_above: usize,
+    /// Encoded values at least this long are stored as blobs.
+    pub(crate) blobs_above: Option<usize>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Sample {
+        name: String,
+        bytes: Vec<u8>,
+        big: u64,
+        ratio: f64,
+        nested: Option<Vec<(i32, String)>>,
+    }
+
+    fn codecs() -> Vec<Codec> {
+        vec![
+            Codec::Json,
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack,
+            #[cfg(feature = "cbor")]
+            Codec::Cbor,
+            #[cfg(feature = "bincode")]
+            Codec::Bincode,
+        ]
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        let sample = Sample {
+            name: "potency".into(),
+            bytes: (0..=255).collect(),
+            big: u64::MAX,
+            ratio: 0.1 + 0.2,
+            nested: Some(vec![(-1, "a".into())]),
+        };
+        for codec in codecs() {
+            let bytes = codec.encode(&sample).unwrap();
+            let decoded: Sample = codec.decode(&bytes).unwrap();
+            assert_eq!(decoded, sample, "{codec:?}");
+            assert_eq!(Codec::from_tag(codec.tag()).unwrap(), codec);
+        }
+        assert!(matches!(
+            Codec::from_tag("nope"),
+            Err(StoreError::UnknownCodec { .. })
+        ));
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let text = "potency ".repeat(10_000).into_bytes();
+        let compressions: &[Compression] = &[
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level: 3 },
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ];
+        for &compression in compressions {
+            let compressed = compression.compress(&text).unwrap().unwrap();
+            assert!(compressed.len() < text.len() / 10, "{compression:?}");
+            let tag = compression.tag().unwrap();
+            assert_eq!(Compression::decompress(tag, &compressed).unwrap(), text);
+            // Incompressible input is left alone.
+            assert!(compression.compress(b"x").unwrap().is_none());
+        }
+        assert!(Compression::None.compress(&text).unwrap().is_none());
+    }
+}
@@ -0,0 +1,176 @@
+//! Encryption at rest.
+//!
+//! Values are sealed with ChaCha20-Poly1305 after compression. Each sealed
+//! value is a random 96-bit nonce followed by the ciphertext, and its row
+//! records the id of the key it was sealed with. The associated data binds
+//! a value to where it is stored (its row key, or its blob hash), so a
+//! value copied to another row fails to open.
+
+use std::collections::HashMap;
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use hmac::Mac;
+
+use crate::{EncryptionSnafu, StoreError, UnknownKeySnafu};
+
+const NONCE_LEN: usize = 12;
+
+/// The keys a store encrypts values with.
+///
+/// New values are sealed with the current key. Older keys added with
+/// [`Keyring::with_old_key`] only open what they sealed, until
+/// [`Store::reencrypt`][crate::Store::reencrypt] moves those rows to the
+/// current key.
+///
+/// ```rust,no_run
+/// # async fn doc(key: [u8; 32], old: [u8; 32]) -> Result<(), potency::StoreError> {
+/// use potency::{Keyring, Store, StoreOptions};
+///
+/// let keyring = Keyring::new("2025-06", key).with_old_key("2024-01", old);
+/// let store = Store::open_with("state.db", StoreOptions::new().encryption(keyring)).await?;
+/// store.reencrypt().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Keyring {
+    current: String,
+    keys: HashMap<String, ChaCha20Poly1305>,
+    hash_keys: Option<hmac::Hmac<sha2::Sha256>>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print key material.
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("hash_keys", &self.hash_keys.is_some())
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// A keyring sealing new values with `key`, recorded as `id`.
+    pub fn new(id: impl Into<String>, key: [u8; 32]) -> Self {
+        let id = id.into();
+        Self {
+            keys: HashMap::from([(id.clone(), ChaCha20Poly1305::new(&key.into()))]),
+            current: id,
+            hash_keys: None,
+        }
+    }
+
+    /// Also open values sealed with `key`, recorded as `id`.
+    pub fn with_old_key(mut self, id: impl Into<String>, key: [u8; 32]) -> Self {
+        self.keys
+            .insert(id.into(), ChaCha20Poly1305::new(&key.into()));
+        self
+    }
+
+    /// Store cache keys as their HMAC-SHA256 under `secret` instead of in
+    /// the clear, for when params themselves are sensitive.
+    ///
+    /// Blobs are then addressed by the same keyed hash rather than a plain
+    /// SHA-256, so their names don't reveal their content either. Entries
+    /// stored before this was turned on, or under another secret, are no
+    /// longer found. The secret is separate from the encryption keys, so
+    /// rotating those keeps every key's hash.
+    pub fn hash_keys(mut self, secret: [u8; 32]) -> Self {
+        // UNWRAP: safe because HMAC takes keys of any length.
+        let mac = <hmac::Hmac<sha2::Sha256> as Mac>::new_from_slice(&secret).unwrap();
+        self.hash_keys = Some(mac);
+        self
+    }
+
+    pub(crate) fn current_id(&self) -> &str {
+        &self.current
+    }
+
+    /// `bytes` sealed with the current key, bound to `aad`.
+    pub(crate) fn seal(&self, aad: &str, bytes: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let cipher = &self.keys[&self.current];
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: bytes,
+            aad: aad.as_bytes(),
+        };
+        let ciphertext = cipher.encrypt(&nonce, payload).map_err(|_| {
+            EncryptionSnafu {
+                message: "encryption failed",
+            }
+            .build()
+        })?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Open `sealed`, which was sealed with key `key_id` and bound to `aad`.
+    pub(crate) fn open(
+        &self,
+        key_id: &str,
+        aad: &str,
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, StoreError> {
+        let cipher = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| UnknownKeySnafu { key_id }.build())?;
+        if sealed.len() < NONCE_LEN {
+            return EncryptionSnafu {
+                message: "sealed value is truncated",
+            }
+            .fail();
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: aad.as_bytes(),
+        };
+        cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                EncryptionSnafu {
+                    message: format!("value does not open with key {key_id:?}"),
+                }
+                .build()
+            })
+    }
+
+    /// The keyed hash of `bytes`, if [`Keyring::hash_keys`] is set.
+    pub(crate) fn keyed_hash(&self, bytes: &[u8]) -> Option<String> {
+        let mut mac = self.hash_keys.clone()?;
+        mac.update(bytes);
+        Some(format!("{:x}", mac.finalize().into_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seal_binds_key_and_aad() {
+        let keyring = Keyring::new("a", [1; 32]);
+        let sealed = keyring.seal("row", b"secret").unwrap();
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(keyring.open("a", "row", &sealed).unwrap(), b"secret");
+        assert!(matches!(
+            keyring.open("a", "other row", &sealed),
+            Err(StoreError::Encryption { .. })
+        ));
+        assert!(matches!(
+            keyring.open("b", "row", &sealed),
+            Err(StoreError::UnknownKey { .. })
+        ));
+        let rotated = Keyring::new("b", [2; 32]).with_old_key("a", [1; 32]);
+        assert_eq!(rotated.open("a", "row", &sealed).unwrap(), b"secret");
+        assert!(rotated.keyed_hash(b"k").is_none());
+        let hashed = rotated.hash_keys([3; 32]);
+        assert_eq!(hashed.keyed_hash(b"k").unwrap().len(), 64);
+    }
+}
@@ -0,0 +1,1990 @@
+//! The SQLite connections behind a [`Store`][crate::Store] and the queries
+//! run against them.
+//!
+//! SQLite calls block, so none of them run on the caller's executor. Each
+//! connection is owned by a dedicated thread that runs jobs sent to it over
+//! a channel and sends each job's result back over an async channel.
+//!
+//! Each connection keeps its prepared statements for its lifetime. With
+//! [`StoreOptions::write_behind`] the writer also groups consecutive jobs
+//! into one transaction, committed at most one flush interval after it
+//! began.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use snafu::OptionExt;
+
+#[cfg(feature = "encryption")]
+pub(crate) use crate::Keyring;
+use crate::{
+    codec::Encoding,
+    deps::{self, Computation},
+    export::{ConflictPolicy, ImportReport, Imported, Record},
+    gc::{GcReport, Roots},
+    memory::Memory,
+    migrate,
+    remote::Remote,
+    Codec, Compression, DatabaseThreadSnafu, HashedKeysSnafu, ImportConflictSnafu,
+    InvalidTableNameSnafu, JournalMode, MissingBlobSnafu, StoreError, StoreOptions,
+    UnknownKeySnafu,
+};
+
+/// Names of the tables a store uses, derived from
+/// [`StoreOptions::table_name`].
+pub(crate) struct Tables {
+    /// Cached values, one row per key.
+    pub(crate) values: String,
+    /// Panics recorded by [`Builder::catch_panics`][crate::Builder::catch_panics].
+    pub(crate) failures: String,
+    /// Schema version; see [`migrate`].
+    pub(crate) meta: String,
+    /// Large values, keyed by the SHA-256 of their encoding; see
+    /// [`StoreOptions::blobs_above`].
+    pub(crate) blobs: String,
+    /// The latest change to each key; see
+    /// [`Store::changes_since`][crate::Store::changes_since].
+    pub(crate) changes: String,
+    /// Tags set with [`Builder::tag`][crate::Builder::tag], one row per key
+    /// and tag.
+    pub(crate) tags: String,
+    /// Which entries each entry read while it was computed; see
+    /// [`Store::dependencies`][crate::Store::dependencies].
+    pub(crate) deps: String,
+}
+
+impl Tables {
+    pub(crate) fn new(name: &str) -> Result<Self, StoreError> {
+        let mut chars = name.chars();
+        let valid = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return InvalidTableNameSnafu { name }.fail();
+        }
+        Ok(Self {
+            values: name.to_owned(),
+            failures: format!("{name}_failures"),
+            meta: format!("{name}_meta"),
+            tags: format!("{name}_tags"),
+            deps: format!("{name}_deps"),
+            blobs: format!("{name}_blobs"),
+            changes: format!("{name}_changes"),
+        })
+    }
+}
+
+/// Without the `encryption` feature no keyring can exist. This stand-in
+/// keeps the code that handles one the same either way.
+#[cfg(not(feature = "encryption"))]
+#[derive(Debug)]
+pub(crate) enum Keyring {}
+
+#[cfg(not(feature = "encryption"))]
+impl Keyring {
+    pub(crate) fn current_id(&self) -> &str {
+        match *self {}
+    }
+
+    pub(crate) fn seal(&self, _: &str, _: &[u8]) -> Result<Vec<u8>, StoreError> {
+        match *self {}
+    }
+
+    pub(crate) fn open(&self, _: &str, _: &str, _: &[u8]) -> Result<Vec<u8>, StoreError> {
+        match *self {}
+    }
+
+    pub(crate) fn keyed_hash(&self, _: &[u8]) -> Option<String> {
+        match *self {}
+    }
+}
+
+/// A unit of work for a connection thread.
+type Job = Box<dyn FnOnce(&Conn<'_>) + Send>;
+
+/// Values written or deleted by the open write-behind transaction, so that
+/// reader connections, which can't see it yet, still observe them.
+type Pending = std::sync::Mutex<HashMap<String, Option<Row>>>;
+
+/// The most jobs one write-behind transaction takes before it is committed
+/// early.
+const MAX_BATCH: usize = 1024;
+
+/// How many pages [`Conn::backup_to`] copies per step.
+const BACKUP_STEP: std::ffi::c_int = 1024;
+
+/// How many keys [`Db::scan`] lists per job.
+pub(crate) const SCAN_PAGE: usize = 256;
+
+/// How many imported entries are handed to [`Conn::import_batches`] at a
+/// time.
+pub(crate) const IMPORT_BATCH: usize = 256;
+
+/// Lists up to `limit` keys in a namespace after a key; see
+/// [`Conn::list_entries`].
+pub(crate) type ScanPage<T> =
+    fn(&Conn<'_>, &str, Option<&str>, usize) -> Result<Vec<(String, T)>, StoreError>;
+
+/// Handles to the connection threads behind a store.
+///
+/// All writes go through the single writer thread. Lookups go through a
+/// pool of read-only connection threads when one is configured (see
+/// [`StoreOptions::readers`]): they share one job queue, so whichever
+/// reader is free takes the next lookup, and in WAL mode none of them wait
+/// on the writer.
+///
+/// The threads exit once every [`Store`][crate::Store] clone sharing this
+/// `Db` has been dropped, the writer after finishing its queue. Dropping
+/// doesn't wait for that; [`Db::close`] does.
+pub(crate) struct Db {
+    writer: mpsc::Sender<Job>,
+    /// Closed once the writer has finished its queue and closed its
+    /// connection.
+    writer_closed: async_channel::Receiver<()>,
+    readers: Option<mpsc::Sender<Job>>,
+    /// Set when writes are batched; see [`StoreOptions::write_behind`].
+    pending: Option<Arc<Pending>>,
+    /// Cache hits not yet recorded, by key: how many, and the last one's
+    /// time. Recorded by a single job on the writer that takes them all,
+    /// so a burst of hits costs one write.
+    hits: Arc<std::sync::Mutex<HashMap<String, (u64, i64)>>>,
+    /// Set when values are also kept in memory; see
+    /// [`StoreOptions::memory_cache`].
+    memory: Option<Memory>,
+    /// Set when the store has a remote tier; see [`StoreOptions::remote`].
+    remote: Option<Remote>,
+}
+
+impl Db {
+    /// Open the database at `path`, apply the pragmas from `options` and,
+    /// unless it is opened read-only, migrate it to the current schema.
+    pub(crate) async fn open(
+        path: impl AsRef<std::path::Path>,
+        options: &StoreOptions,
+    ) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_owned();
+        let tables = Arc::new(Tables::new(&options.table_name)?);
+        // Every connection to `":memory:"` is its own database, so an
+        // in-memory store can't have readers.
+        let in_memory = path.as_os_str().is_empty() || path.as_os_str() == ":memory:";
+        let reader_count = if in_memory { 0 } else { options.readers };
+        // A read-only store has nothing to batch.
+        let flush_interval = options.write_behind.filter(|_| !options.read_only);
+        let pending = flush_interval.map(|_| Arc::new(Pending::default()));
+
+        let (writer, writer_closed) = {
+            let path = path.clone();
+            let options = options.clone();
+            let worker = Worker {
+                name: "potency-writer",
+                tables: tables.clone(),
+                pending: pending.clone(),
+                keyring: options.encryption.clone(),
+                flush_interval,
+            };
+            let (sender, jobs) = mpsc::channel::<Job>();
+            let closed = spawn_worker(worker, jobs.into(), move |tables| {
+                open_writer(&path, &options, tables, reader_count > 0)
+            })
+            .await?;
+            (sender, closed)
+        };
+
+        // Readers are opened after migrating so they see the final schema.
+        let readers = if reader_count > 0 {
+            let (sender, jobs) = mpsc::channel::<Job>();
+            let jobs = Arc::new(std::sync::Mutex::new(jobs));
+            for _ in 0..reader_count {
+                let path = path.clone();
+                let options = options.clone();
+                let worker = Worker {
+                    name: "potency-reader",
+                    tables: tables.clone(),
+                    pending: pending.clone(),
+                    keyring: options.encryption.clone(),
+                    flush_interval: None,
+                };
+                spawn_worker(worker, jobs.clone().into(), move |_| {
+                    let flags = sqlite::OpenFlags::default().with_read_only();
+                    open_connection(&path, flags, &options)
+                })
+                .await?;
+            }
+            Some(sender)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            writer,
+            writer_closed,
+            readers,
+            pending,
+            hits: Arc::default(),
+            memory: (options.memory_cache > 0).then(|| Memory::new(options.memory_cache)),
+            remote: options
+                .remote
+                .as_deref()
+                .map(|url| Remote::new(url, options.encryption.clone()))
+                .transpose()?,
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn has_readers(&self) -> bool {
+        self.readers.is_some()
+    }
+
+    /// Run `f` on the writer connection.
+    pub(crate) async fn write<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Conn<'_>) -> Result<R, StoreError> + Send + 'static,
+    ) -> Result<R, StoreError> {
+        submit(&self.writer, f).await
+    }
+
+    /// Run `f` on a free reader connection, or on the writer if the store
+    /// has no readers. `f` must not write.
+    pub(crate) async fn read<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Conn<'_>) -> Result<R, StoreError> + Send + 'static,
+    ) -> Result<R, StoreError> {
+        submit(self.readers.as_ref().unwrap_or(&self.writer), f).await
+    }
+
+    pub(crate) async fn fetch_value(&self, key: &str) -> Result<Option<Row>, StoreError> {
+        let key = key.to_owned();
+        self.read(move |conn| conn.fetch_value(&key)).await
+    }
+
+    pub(crate) async fn delete_value(&self, key: &str) -> Result<(), StoreError> {
+        if let Some(memory) = &self.memory {
+            memory.remove(key);
+        }
+        let key = key.to_owned();
+        self.write(move |conn| conn.delete_value(&key)).await
+    }
+
+    /// Close the database, waiting until the writer has finished its queue
+    /// and closed its connection.
+    pub(crate) async fn close(self) {
+        let closed = self.writer_closed.clone();
+        drop(self);
+        // Only ever closed, never sent to.
+        let _ = closed.recv().await;
+    }
+
+    /// The in-memory tier, if the store has one.
+    pub(crate) fn memory(&self) -> Option<&Memory> {
+        self.memory.as_ref()
+    }
+
+    /// The remote tier, if the store has one.
+    pub(crate) fn remote(&self) -> Option<&Remote> {
+        self.remote.as_ref()
+    }
+
+    /// A computation of an entry in this database, to record what it reads.
+    pub(crate) fn computation(&self) -> Rc<Computation> {
+        Computation::new(self as *const Db as usize)
+    }
+
+    /// Record that the computation running, if it is one of this
+    /// database's, read the entry under `key`.
+    pub(crate) fn depended_on(&self, key: &str) {
+        deps::record(self as *const Db as usize, key)
+    }
+
+    /// Like [`Db::read`], but sees writes in the open write-behind
+    /// transaction that [`Pending`] doesn't track, by running on the writer
+    /// when writes are batched.
+    pub(crate) async fn read_latest<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Conn<'_>) -> Result<R, StoreError> + Send + 'static,
+    ) -> Result<R, StoreError> {
+        match self.pending {
+            Some(_) => self.write(f).await,
+            None => self.read(f).await,
+        }
+    }
+
+    pub(crate) async fn fetch_panics(&self, key: &str) -> Result<u32, StoreError> {
+        let key = key.to_owned();
+        self.read_latest(move |conn| conn.fetch_panics(&key)).await
+    }
+
+    /// Note a cache hit on `key`, without waiting for it to be recorded.
+    pub(crate) fn record_hit(&self, key: &str) {
+        let mut hits = self.hits.lock().unwrap_or_else(|p| p.into_inner());
+        let schedule = hits.is_empty();
+        let hit = hits.entry(key.to_owned()).or_default();
+        hit.0 += 1;
+        hit.1 = now_millis();
+        drop(hits);
+        if schedule {
+            let hits = self.hits.clone();
+            let job: Job = Box::new(move |conn| {
+                let hits = std::mem::take(&mut *hits.lock().unwrap_or_else(|p| p.into_inner()));
+                if let Err(e) = conn.savepoint(|| conn.record_hits(&hits)) {
+                    log::warn!("could not record {} cache hits: {e}", hits.len());
+                }
+            });
+            // A closed queue means the store is shutting down.
+            let _ = self.writer.send(job);
+        }
+    }
+
+    pub(crate) async fn entry_info(&self, key: &str) -> Result<Option<EntryInfo>, StoreError> {
+        let key = key.to_owned();
+        self.read_latest(move |conn| conn.entry_info(&key)).await
+    }
+
+    pub(crate) async fn record_panic(&self, key: &str, message: &str) -> Result<(), StoreError> {
+        let (key, message) = (key.to_owned(), message.to_owned());
+        self.write(move |conn| conn.record_panic(&key, &message))
+            .await
+    }
+
+    pub(crate) async fn clear_panics(&self, key: &str) -> Result<(), StoreError> {
+        let key = key.to_owned();
+        self.write(move |conn| conn.clear_panics(&key)).await
+    }
+
+    pub(crate) async fn stats(&self) -> Result<Stats, StoreError> {
+        self.read_latest(|conn| conn.stats()).await
+    }
+
+    /// Stream what `page` lists under the namespace `prefix`, one page of
+    /// [`SCAN_PAGE`] keys per job, so a long scan never holds a connection
+    /// for long.
+    pub(crate) fn scan<T: Send + 'static>(
+        self: &Arc<Self>,
+        prefix: String,
+        page: ScanPage<T>,
+    ) -> impl futures_lite::Stream<Item = Result<(String, T), StoreError>> + Unpin + 'static {
+        struct Scan<T> {
+            db: Arc<Db>,
+            prefix: String,
+            after: Option<String>,
+            buffered: std::vec::IntoIter<(String, T)>,
+            done: bool,
+        }
+        let scan = Scan {
+            db: self.clone(),
+            prefix,
+            after: None,
+            buffered: Vec::new().into_iter(),
+            done: false,
+        };
+        Box::pin(futures_lite::stream::unfold(
+            scan,
+            move |mut scan| async move {
+                loop {
+                    if let Some(item) = scan.buffered.next() {
+                        return Some((Ok(item), scan));
+                    }
+                    if scan.done {
+                        return None;
+                    }
+                    let (prefix, after) = (scan.prefix.clone(), scan.after.take());
+                    let listed = scan
+                        .db
+                        .read_latest(move |conn| page(conn, &prefix, after.as_deref(), SCAN_PAGE))
+                        .await;
+                    match listed {
+                        Ok(items) => {
+                            scan.done = items.len() < SCAN_PAGE;
+                            scan.after = items.last().map(|(key, _)| key.clone());
+                            scan.buffered = items.into_iter();
+                        }
+                        Err(e) => {
+                            scan.done = true;
+                            return Some((Err(e), scan));
+                        }
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Commit the open write-behind transaction, if any.
+    pub(crate) async fn flush(&self) -> Result<(), StoreError> {
+        self.write(|conn| conn.commit_batch()).await
+    }
+}
+
+async fn submit<R: Send + 'static>(
+    queue: &mpsc::Sender<Job>,
+    f: impl FnOnce(&Conn<'_>) -> Result<R, StoreError> + Send + 'static,
+) -> Result<R, StoreError> {
+    let (reply, result) = async_channel::bounded(1);
+    queue
+        .send(Box::new(move |conn| {
+            let _ = reply.send_blocking(f(conn));
+        }))
+        .ok()
+        .context(DatabaseThreadSnafu)?;
+    // A closed reply channel means the job panicked.
+    result.recv().await.ok().context(DatabaseThreadSnafu)?
+}
+
+/// Where a connection thread takes its jobs from.
+enum Jobs {
+    /// A queue of its own.
+    Own(mpsc::Receiver<Job>),
+    /// A queue shared with other threads; whichever is idle takes the next
+    /// job.
+    Shared(Arc<std::sync::Mutex<mpsc::Receiver<Job>>>),
+}
+
+impl From<mpsc::Receiver<Job>> for Jobs {
+    fn from(jobs: mpsc::Receiver<Job>) -> Self {
+        Jobs::Own(jobs)
+    }
+}
+
+impl From<Arc<std::sync::Mutex<mpsc::Receiver<Job>>>> for Jobs {
+    fn from(jobs: Arc<std::sync::Mutex<mpsc::Receiver<Job>>>) -> Self {
+        Jobs::Shared(jobs)
+    }
+}
+
+/// Why [`Jobs::recv`] returned without a job.
+enum Idle {
+    /// The deadline passed.
+    Timeout,
+    /// Every sender is gone.
+    Closed,
+}
+
+impl Jobs {
+    /// The next job, waiting no later than `deadline` if one is given.
+    fn recv(&self, deadline: Option<Instant>) -> Result<Job, Idle> {
+        match (self, deadline) {
+            (Jobs::Own(jobs), None) => jobs.recv().map_err(|_| Idle::Closed),
+            (Jobs::Own(jobs), Some(deadline)) => jobs
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(|e| match e {
+                    mpsc::RecvTimeoutError::Timeout => Idle::Timeout,
+                    mpsc::RecvTimeoutError::Disconnected => Idle::Closed,
+                }),
+            // Only the writer batches, and it has a queue of its own, so a
+            // shared queue is never given a deadline.
+            //
+            // A poisoned lock means a sibling thread panicked while idle;
+            // the receiver itself is still fine.
+            (Jobs::Shared(jobs), _) => jobs
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .recv()
+                .map_err(|_| Idle::Closed),
+        }
+    }
+}
+
+/// What a connection thread needs besides its connection and jobs.
+struct Worker {
+    name: &'static str,
+    tables: Arc<Tables>,
+    pending: Option<Arc<Pending>>,
+    keyring: Option<Arc<Keyring>>,
+    /// Batch writes, committing at most this long after a batch begins.
+    flush_interval: Option<Duration>,
+}
+
+/// Spawn a thread that opens a connection with `open` and then runs jobs
+/// until its queue closes. Resolves once the connection is open, to a
+/// channel that closes when the thread has closed it again.
+async fn spawn_worker(
+    worker: Worker,
+    jobs: Jobs,
+    open: impl FnOnce(&Tables) -> Result<sqlite::Connection, StoreError> + Send + 'static,
+) -> Result<async_channel::Receiver<()>, StoreError> {
+    let (ready, opened) = async_channel::bounded(1);
+    let (done, closed) = async_channel::bounded::<()>(1);
+    std::thread::Builder::new()
+        .name(worker.name.to_owned())
+        .spawn(move || {
+            let Worker {
+                tables,
+                pending,
+                keyring,
+                flush_interval,
+                ..
+            } = worker;
+            let connection = match open(&tables) {
+                Ok(connection) => {
+                    let _ = ready.send_blocking(Ok(()));
+                    connection
+                }
+                Err(e) => {
+                    let _ = ready.send_blocking(Err(e));
+                    return;
+                }
+            };
+            let conn = Conn::new(&connection, &tables, pending.as_deref(), keyring.as_deref());
+            loop {
+                let job = match jobs.recv(conn.batch_deadline.get()) {
+                    Ok(job) => job,
+                    Err(idle) => {
+                        if let Err(e) = conn.commit_batch() {
+                            log::error!("write-behind commit failed, writes lost: {e}");
+                        }
+                        match idle {
+                            Idle::Timeout => continue,
+                            Idle::Closed => break,
+                        }
+                    }
+                };
+                if let Some(interval) = flush_interval {
+                    if let Err(e) = conn.begin_batch(interval) {
+                        log::error!("could not begin write-behind batch: {e}");
+                    }
+                }
+                // A panicking job drops its reply sender, which the caller
+                // sees as an error; the thread carries on.
+                let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| job(&conn)));
+                if conn.batch_size.get() >= MAX_BATCH {
+                    if let Err(e) = conn.commit_batch() {
+                        log::error!("write-behind commit failed, writes lost: {e}");
+                    }
+                }
+            }
+            drop(conn);
+            drop(connection);
+            drop(done);
+        })?;
+    opened.recv().await.ok().context(DatabaseThreadSnafu)??;
+    Ok(closed)
+}
+
+/// Open the writer connection and bring the schema up to date.
+fn open_writer(
+    path: &std::path::Path,
+    options: &StoreOptions,
+    tables: &Tables,
+    has_readers: bool,
+) -> Result<sqlite::Connection, StoreError> {
+    let journal_mode = match options.journal_mode {
+        None if has_readers => Some(JournalMode::Wal),
+        journal_mode => journal_mode,
+    };
+    let flags = if options.read_only {
+        sqlite::OpenFlags::default().with_read_only()
+    } else {
+        sqlite::OpenFlags::default().with_create().with_read_write()
+    };
+    let writer = open_connection(path, flags, options)?;
+    if let (Some(journal_mode), false) = (journal_mode, options.read_only) {
+        writer.execute(format!("PRAGMA journal_mode = {}", journal_mode.as_sql()))?;
+    }
+    if options.read_only {
+        migrate::ensure_current(&writer, tables)?;
+    } else {
+        migrate::migrate(&writer, tables)?;
+    }
+    Ok(writer)
+}
+
+fn open_connection(
+    path: &std::path::Path,
+    flags: sqlite::OpenFlags,
+    options: &StoreOptions,
+) -> Result<sqlite::Connection, StoreError> {
+    let mut connection = sqlite::Connection::open_with_flags(path, flags)?;
+    if let Some(timeout) = options.busy_timeout {
+        connection.set_busy_timeout(timeout.as_millis() as usize)?;
+    }
+    if let Some(synchronous) = options.synchronous {
+        connection.execute(format!("PRAGMA synchronous = {}", synchronous.as_sql()))?;
+    }
+    Ok(connection)
+}
+
+/// A connection, borrowed by a job on its thread.
+pub(crate) struct Conn<'a> {
+    connection: &'a sqlite::Connection,
+    tables: &'a Tables,
+    /// Prepared statements, keyed by query name. A statement is taken out
+    /// while in use, so nested queries never share one.
+    statements: RefCell<HashMap<&'static str, sqlite::Statement<'a>>>,
+    pending: Option<&'a Pending>,
+    /// Set with [`StoreOptions::encryption`].
+    keyring: Option<&'a Keyring>,
+    /// When the open write-behind transaction must be committed, if one is
+    /// open.
+    batch_deadline: Cell<Option<Instant>>,
+    /// Jobs run in the open write-behind transaction.
+    batch_size: Cell<usize>,
+}
+
+/// Storage figures for a whole store, from [`Store::stats`][crate::Store::stats].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Stored values, expired or not.
+    pub entries: u64,
+    /// How many of those, or of the blobs holding them, are compressed.
+    pub compressed_entries: u64,
+    /// Size of the values as encoded, before compression.
+    pub raw_bytes: u64,
+    /// Size of the values as stored, counting each blob once.
+    pub stored_bytes: u64,
+    /// Blobs in the blobs table; see
+    /// [`StoreOptions::blobs_above`][crate::StoreOptions::blobs_above].
+    pub blobs: u64,
+}
+
+impl Stats {
+    /// `raw_bytes / stored_bytes`: 1.0 without compression or shared blobs,
+    /// higher the more they save.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+/// How far [`Store::backup_to`][crate::Store::backup_to] has got.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// Database pages copied so far.
+    pub copied_pages: u64,
+    /// Database pages in all.
+    pub total_pages: u64,
+}
+
+impl BackupProgress {
+    /// `copied_pages / total_pages`, from 0.0 to 1.0.
+    pub fn fraction(&self) -> f64 {
+        if self.total_pages == 0 {
+            return 1.0;
+        }
+        self.copied_pages as f64 / self.total_pages as f64
+    }
+}
+
+/// What a store knows about one entry, from
+/// [`Store::entry_info`][crate::Store::entry_info].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// When the value was stored, if it was stored by a `potency` that
+    /// recorded it.
+    pub created_at: Option<std::time::SystemTime>,
+    /// When the value expires, if it has a [TTL](crate::Builder::ttl).
+    pub expires_at: Option<std::time::SystemTime>,
+    /// When the value was last returned as a cache hit.
+    pub last_accessed_at: Option<std::time::SystemTime>,
+    /// How many times the value has been returned as a cache hit.
+    ///
+    /// Hits are recorded shortly after they happen, not as part of them,
+    /// and only by stores that write (see [`Mode`][crate::Mode]).
+    pub hit_count: u64,
+    /// How long the function took to produce the value.
+    pub compute_duration: Option<Duration>,
+    /// The version of `potency` that stored the value.
+    pub potency_version: Option<String>,
+    /// Whether the entry is [pinned](crate::Builder::pin).
+    pub pinned: bool,
+    /// The entry's [tags](crate::Builder::tag), in order.
+    pub tags: Vec<String>,
+}
+
+impl EntryInfo {
+    /// Compute time saved by the cache so far: one
+    /// [`EntryInfo::compute_duration`] per hit.
+    pub fn time_saved(&self) -> Option<Duration> {
+        let hits = u32::try_from(self.hit_count).unwrap_or(u32::MAX);
+        self.compute_duration.map(|d| d.saturating_mul(hits))
+    }
+}
+
+impl From<Stamps> for EntryInfo {
+    fn from(stamps: Stamps) -> Self {
+        let time = |millis: Option<i64>| {
+            // Rows from before timestamps were recorded have 0.
+            millis
+                .filter(|&millis| millis > 0)
+                .map(|millis| std::time::UNIX_EPOCH + Duration::from_millis(millis as u64))
+        };
+        EntryInfo {
+            created_at: time(Some(stamps.created_at)),
+            expires_at: time(stamps.expires_at),
+            last_accessed_at: time(stamps.last_accessed_at),
+            hit_count: stamps.hit_count,
+            compute_duration: stamps
+                .compute_duration
+                .map(|micros| Duration::from_micros(micros as u64)),
+            potency_version: stamps.potency_version,
+            pinned: stamps.pinned,
+            tags: stamps.tags,
+        }
+    }
+}
+
+/// The bookkeeping columns of a row, as stored. Times are Unix time in
+/// milliseconds.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Stamps {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_accessed_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) hit_count: u64,
+    /// In microseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) compute_duration: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) potency_version: Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) pinned: bool,
+    /// Kept in the tags table, so [`Stamps::read`] leaves them empty.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub(crate) tags: Vec<String>,
+}
+
+fn is_zero<N: Default + PartialEq>(n: &N) -> bool {
+    *n == N::default()
+}
+
+impl Stamps {
+    /// The stamps of a value stored now, by this version of `potency`.
+    pub(crate) fn new(ttl: Option<Duration>, compute_duration: Option<Duration>) -> Self {
+        let now = now_millis();
+        Stamps {
+            created_at: now,
+            expires_at: ttl.map(|ttl| now.saturating_add(ttl.as_millis() as i64)),
+            last_accessed_at: None,
+            hit_count: 0,
+            compute_duration: compute_duration.map(|d| d.as_micros() as i64),
+            potency_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            pinned: false,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Read the stamps from the current row of `statement`.
+    fn read(statement: &sqlite::Statement<'_>) -> Result<Self, StoreError> {
+        Ok(Stamps {
+            created_at: statement.read("created_at")?,
+            expires_at: statement.read("expires_at")?,
+            last_accessed_at: statement.read("last_accessed_at")?,
+            hit_count: statement.read::<i64, _>("hit_count")? as u64,
+            compute_duration: statement.read("compute_duration")?,
+            potency_version: statement.read("potency_version")?,
+            pinned: statement.read::<i64, _>("pinned")? != 0,
+            tags: Vec::new(),
+        })
+    }
+}
+
+/// A row read back from the values table.
+#[derive(Clone)]
+pub(crate) struct Row {
+    pub(crate) codec: Codec,
+    pub(crate) bytes: Vec<u8>,
+    /// Unix time in milliseconds, or `None` if the row never expires.
+    pub(crate) expires_at: Option<i64>,
+}
+
+impl Row {
+    pub(crate) fn decode<T: serde::de::DeserializeOwned>(&self) -> Result<T, StoreError> {
+        self.codec.decode(&self.bytes)
+    }
+
+    pub(crate) fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+
+    /// Whether an expired row may still be served while it is refreshed.
+    pub(crate) fn is_servable_stale(&self, now: i64, window: Option<std::time::Duration>) -> bool {
+        match (self.expires_at, window) {
+            (Some(at), Some(window)) => now < at.saturating_add(window.as_millis() as i64),
+            _ => false,
+        }
+    }
+}
+
+/// The key of a blob: the hex SHA-256 of the encoded value, or its keyed
+/// hash if the keyring hashes keys.
+fn blob_hash(bytes: &[u8], keyring: Option<&Keyring>) -> String {
+    use sha2::Digest;
+    keyring
+        .and_then(|keyring| keyring.keyed_hash(bytes))
+        .unwrap_or_else(|| format!("{:x}", sha2::Sha256::digest(bytes)))
+}
+
+pub(crate) fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+impl<'a> Conn<'a> {
+    fn new(
+        connection: &'a sqlite::Connection,
+        tables: &'a Tables,
+        pending: Option<&'a Pending>,
+        keyring: Option<&'a Keyring>,
+    ) -> Self {
+        Self {
+            connection,
+            tables,
+            statements: RefCell::default(),
+            pending,
+            keyring,
+            batch_deadline: Cell::new(None),
+            batch_size: Cell::new(0),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn connection(&self) -> &sqlite::Connection {
+        self.connection
+    }
+
+    /// Run `f` with the cached statement `name`, preparing it from `sql`
+    /// first if needed.
+    fn with_statement<R>(
+        &self,
+        name: &'static str,
+        sql: impl FnOnce(&Tables) -> String,
+        f: impl FnOnce(&mut sqlite::Statement<'a>) -> Result<R, StoreError>,
+    ) -> Result<R, StoreError> {
+        let cached = self.statements.borrow_mut().remove(name);
+        let mut statement = match cached {
+            Some(statement) => statement,
+            None => self.connection.prepare(sql(self.tables))?,
+        };
+        let result = f(&mut statement);
+        // Reset so a statement that wasn't stepped to completion doesn't
+        // hold its read transaction open. One that won't reset is dropped.
+        if statement.reset().is_ok() {
+            self.statements.borrow_mut().insert(name, statement);
+        }
+        result
+    }
+
+    /// Whether keys are stored as their keyed hash.
+    fn hashes_keys(&self) -> bool {
+        self.keyring
+            .is_some_and(|keyring| keyring.keyed_hash(&[]).is_some())
+    }
+
+    /// The key `key` is stored under: itself, or its keyed hash if the
+    /// keyring hashes keys.
+    fn row_key<'k>(&self, key: &'k str) -> std::borrow::Cow<'k, str> {
+        match self
+            .keyring
+            .and_then(|keyring| keyring.keyed_hash(key.as_bytes()))
+        {
+            Some(hash) => hash.into(),
+            None => key.into(),
+        }
+    }
+
+    /// Seal `bytes` for storage at `aad` if the store encrypts, returning
+    /// what to store and the id of the key used.
+    fn seal<'b>(
+        &self,
+        aad: &str,
+        bytes: &'b [u8],
+    ) -> Result<(std::borrow::Cow<'b, [u8]>, Option<&'a str>), StoreError> {
+        match self.keyring {
+            Some(keyring) => Ok((keyring.seal(aad, bytes)?.into(), Some(keyring.current_id()))),
+            None => Ok((bytes.into(), None)),
+        }
+    }
+
+    /// Open `bytes` stored at `aad`, if they were sealed with `key_id`.
+    fn open(
+        &self,
+        key_id: Option<String>,
+        aad: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Vec<u8>, StoreError> {
+        let Some(key_id) = key_id else {
+            return Ok(bytes);
+        };
+        match self.keyring {
+            Some(keyring) => keyring.open(&key_id, aad, &bytes),
+            None => UnknownKeySnafu { key_id }.fail(),
+        }
+    }
+
+    /// Run `f` atomically. A savepoint rather than a transaction, so it
+    /// nests inside an open write-behind transaction.
+    pub(crate) fn savepoint<R>(
+        &self,
+        f: impl FnOnce() -> Result<R, StoreError>,
+    ) -> Result<R, StoreError> {
+        self.connection.execute("SAVEPOINT potency")?;
+        match f() {
+            Ok(result) => {
+                self.connection.execute("RELEASE potency")?;
+                Ok(result)
+            }
+            Err(e) => {
+                let _ = self.connection.execute("ROLLBACK TO potency");
+                let _ = self.connection.execute("RELEASE potency");
+                Err(e)
+            }
+        }
+    }
+
+    /// Open a write-behind transaction unless one is already open.
+    fn begin_batch(&self, interval: Duration) -> Result<(), StoreError> {
+        if self.batch_deadline.get().is_none() {
+            self.connection.execute("BEGIN IMMEDIATE")?;
+            self.batch_deadline.set(Some(Instant::now() + interval));
+        }
+        self.batch_size.set(self.batch_size.get() + 1);
+        Ok(())
+    }
+
+    /// Commit the open write-behind transaction, if any, rolling it back if
+    /// the commit fails.
+    pub(crate) fn commit_batch(&self) -> Result<(), StoreError> {
+        if self.batch_deadline.take().is_none() {
+            return Ok(());
+        }
+        let jobs = self.batch_size.replace(0);
+        log::trace!("committing {jobs} batched jobs");
+        let result = self.connection.execute("COMMIT");
+        if result.is_err() {
+            let _ = self.connection.execute("ROLLBACK");
+        }
+        if let Some(pending) = self.pending {
+            pending
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clear();
+        }
+        Ok(result?)
+    }
+
+    /// Copy the whole database to `path` with SQLite's online backup API,
+    /// [`BACKUP_STEP`] pages at a time, calling `progress` after each step.
+    ///
+    /// The copy is read from one snapshot, so writes made meanwhile by other
+    /// connections neither tear it nor restart it. It is written next to
+    /// `path` and renamed over it once complete.
+    pub(crate) fn backup_to(
+        &self,
+        path: &std::path::Path,
+        progress: &mut dyn FnMut(BackupProgress),
+    ) -> Result<BackupProgress, StoreError> {
+        use sqlite::ffi;
+
+        // The source can't be mid-write.
+        self.commit_batch()?;
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = std::path::PathBuf::from(partial);
+        let _ = std::fs::remove_file(&partial);
+        let destination = sqlite::Connection::open(&partial)?;
+        let error = |code: std::ffi::c_int| {
+            // SAFETY: the destination connection is open, and SQLite
+            // returns a NUL-terminated message that lives until its next
+            // call on that connection, which is after this copies it.
+            let message =
+                unsafe { std::ffi::CStr::from_ptr(ffi::sqlite3_errmsg(destination.as_raw())) };
+            StoreError::from(sqlite::Error {
+                code: Some(code as isize),
+                message: Some(message.to_string_lossy().into_owned()),
+            })
+        };
+
+        // Pin one snapshot for every step.
+        self.connection
+            .execute("BEGIN; SELECT count(*) FROM sqlite_schema")?;
+        // SAFETY: both connections stay open until the backup is finished
+        // below, and neither is used by anything else meanwhile.
+        let result = unsafe {
+            let backup = ffi::sqlite3_backup_init(
+                destination.as_raw(),
+                c"main".as_ptr(),
+                self.connection.as_raw(),
+                c"main".as_ptr(),
+            );
+            if backup.is_null() {
+                Err(error(ffi::sqlite3_errcode(destination.as_raw())))
+            } else {
+                let mut step = ffi::SQLITE_OK;
+                while step == ffi::SQLITE_OK {
+                    step = ffi::sqlite3_backup_step(backup, BACKUP_STEP);
+                    let total = ffi::sqlite3_backup_pagecount(backup) as u64;
+                    let remaining = ffi::sqlite3_backup_remaining(backup) as u64;
+                    progress(BackupProgress {
+                        copied_pages: total - remaining,
+                        total_pages: total,
+                    });
+                }
+                let total_pages = ffi::sqlite3_backup_pagecount(backup) as u64;
+                match (step, ffi::sqlite3_backup_finish(backup)) {
+                    (ffi::SQLITE_DONE, ffi::SQLITE_OK) => Ok(BackupProgress {
+                        copied_pages: total_pages,
+                        total_pages,
+                    }),
+                    (ffi::SQLITE_DONE, code) | (code, _) => Err(error(code)),
+                }
+            }
+        };
+        let _ = self.connection.execute("COMMIT");
+        drop(destination);
+        match result {
+            Ok(done) => {
+                std::fs::rename(&partial, path)?;
+                Ok(done)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+
+    /// Note a write made in the open write-behind transaction.
+    fn set_pending(&self, key: &str, row: Option<Row>) {
+        if let (Some(pending), Some(_)) = (self.pending, self.batch_deadline.get()) {
+            pending
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .insert(key.to_owned(), row);
+        }
+    }
+
+    pub(crate) fn fetch_value(&self, key: &str) -> Result<Option<Row>, StoreError> {
+        log::trace!("fetching {key}");
+        let key = &*self.row_key(key);
+        if let Some(pending) = self.pending {
+            let pending = pending
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(row) = pending.get(key) {
+                return Ok(row.clone());
+            }
+        }
+        self.fetch_row(key)
+    }
+
+    /// Read the row stored under the row key `key`, ignoring [`Pending`].
+    fn fetch_row(&self, key: &str) -> Result<Option<Row>, StoreError> {
+        self.with_statement(
+            "fetch_value",
+            |tables| {
+                format!(
+                    r#"SELECT v.codec, v.expires_at, v.blob,
+                        CASE WHEN v.blob IS NULL THEN v.value ELSE b.value END AS value,
+                        CASE WHEN v.blob IS NULL THEN v.compression ELSE b.compression END
+                            AS compression,
+                        CASE WHEN v.blob IS NULL THEN v.key_id ELSE b.key_id END AS key_id
+                    FROM "{}" v LEFT JOIN "{}" b ON b.hash = v.blob
+                    WHERE v.key = :key"#,
+                    tables.values, tables.blobs
+                )
+            },
+            |statement| {
+                statement.bind((":key", key))?;
+                match statement.next()? {
+                    sqlite::State::Row => {
+                        // JSON rows from before codecs are TEXT, which
+                        // reads back as its UTF-8 bytes.
+                        let blob = statement.read::<Option<String>, _>("blob")?;
+                        let Some(bytes) = statement.read::<Option<Vec<u8>>, _>("value")? else {
+                            // UNWRAP: safe because only a blob's value can be NULL.
+                            return MissingBlobSnafu {
+                                hash: blob.unwrap(),
+                            }
+                            .fail();
+                        };
+                        let key_id = statement.read::<Option<String>, _>("key_id")?;
+                        let mut bytes = self.open(key_id, blob.as_deref().unwrap_or(key), bytes)?;
+                        if let Some(tag) = statement.read::<Option<String>, _>("compression")? {
+                            bytes = Compression::decompress(&tag, &bytes)?;
+                        }
+                        let codec = Codec::from_tag(&statement.read::<String, _>("codec")?)?;
+                        let expires_at = statement.read::<Option<i64>, _>("expires_at")?;
+                        Ok(Some(Row {
+                            codec,
+                            bytes,
+                            expires_at,
+                        }))
+                    }
+                    sqlite::State::Done => Ok(None),
+                }
+            },
+        )
+    }
+
+    /// Store `bytes`, encoded with `encoding.codec`, compressing them first
+    /// and moving them to the blobs table if `encoding` says so.
+    pub(crate) fn store_value(
+        &self,
+        key: &str,
+        encoding: Encoding,
+        bytes: &[u8],
+        stamps: &Stamps,
+    ) -> Result<(), StoreError> {
+        self.write_row(&self.row_key(key), encoding, bytes, stamps)
+    }
+
+    /// Write the row for the row key `key`, replacing any row already
+    /// there. See [`Conn::store_value`].
+    fn write_row(
+        &self,
+        key: &str,
+        encoding: Encoding,
+        bytes: &[u8],
+        stamps: &Stamps,
+    ) -> Result<(), StoreError> {
+        let blob = encoding
+            .blobs_above
+            .filter(|&above| bytes.len() >= above)
+            .map(|_| blob_hash(bytes, self.keyring));
+        let compress = |bytes: &[u8]| match bytes.len() >= encoding.compress_above {
+            true => encoding.compression.compress(bytes),
+            false => Ok(None),
+        };
+        let compressed = match &blob {
+            // Identical values share one blob, so only the first is
+            // compressed and written.
+            Some(hash) if self.has_blob(hash)? => None,
+            Some(hash) => {
+                let compressed = compress(bytes)?;
+                let compression = compressed.as_ref().and(encoding.compression.tag());
+                let (sealed, key_id) = self.seal(hash, compressed.as_deref().unwrap_or(bytes))?;
+                self.insert_blob(hash, &sealed, compression, key_id)?;
+                None
+            }
+            None => compress(bytes)?,
+        };
+        let (stored, compression) = match (&blob, &compressed) {
+            (Some(_), _) => (&[][..], None),
+            (None, Some(compressed)) => (compressed.as_slice(), encoding.compression.tag()),
+            (None, None) => (bytes, None),
+        };
+        let (stored, key_id) = match &blob {
+            Some(_) => (stored.into(), None),
+            None => self.seal(key, stored)?,
+        };
+        log::trace!(
+            "storing key {key}: {} {:?} bytes, {} stored{}",
+            bytes.len(),
+            encoding.codec,
+            stored.len(),
+            match &blob {
+                Some(hash) => format!(" in blob {hash}"),
+                None => String::new(),
+            }
+        );
+        self.with_statement(
+            "write_row",
+            |tables| {
+                format!(
+                    r#"INSERT OR REPLACE INTO "{}"
+                    (key, value, codec, compression, raw_size, blob, key_id, created_at,
+                        expires_at, last_accessed_at, hit_count, compute_duration,
+                        potency_version, pinned)
+                    VALUES (:key, :value, :codec, :compression, :raw_size, :blob, :key_id,
+                        :created_at, :expires_at, :last_accessed_at, :hit_count,
+                        :compute_duration, :potency_version, :pinned)"#,
+                    tables.values
+                )
+            },
+            |statement| {
+                statement.bind(&[(":key", key), (":codec", encoding.codec.tag())][..])?;
+                statement.bind((":value", &*stored))?;
+                statement.bind((":key_id", key_id))?;
+                statement.bind((":compression", compression))?;
+                statement.bind((":raw_size", bytes.len() as i64))?;
+                statement.bind((":blob", blob.as_deref()))?;
+                statement.bind((":created_at", stamps.created_at))?;
+                statement.bind((":expires_at", stamps.expires_at))?;
+                statement.bind((":last_accessed_at", stamps.last_accessed_at))?;
+                statement.bind((":hit_count", stamps.hit_count as i64))?;
+                statement.bind((":compute_duration", stamps.compute_duration))?;
+                statement.bind((":potency_version", stamps.potency_version.as_deref()))?;
+                statement.bind((":pinned", stamps.pinned as i64))?;
+                let _ = statement.next()?;
+                Ok(())
+            },
+        )?;
+        self.write_tags(key, &stamps.tags)?;
+        self.write_deps(key, &[])?;
+        self.set_pending(
+            key,
+            Some(Row {
+                codec: encoding.codec,
+                bytes: bytes.to_vec(),
+                expires_at: stamps.expires_at,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Replace the tags of the row key `key` with `tags`.
+    fn write_tags(&self, key: &str, tags: &[String]) -> Result<(), StoreError> {
+        self.with_statement(
+            "clear_tags",
+            |tables| format!(r#"DELETE FROM "{}" WHERE key = :key"#, tables.tags),
+            |statement| {
+                statement.bind((":key", key))?;
+                let _ = statement.next()?;
+                Ok(())
+            },
+        )?;
+        for tag in tags {
+            self.with_statement(
+                "add_tag",
+                |tables| {
+                    format!(
+                        r#"INSERT OR IGNORE INTO "{}" (key, tag) VALUES (:key, :tag)"#,
+                        tables.tags
+                    )
+                },
+                |statement| {
+                    statement.bind(&[(":key", key), (":tag", tag.as_str())][..])?;
+                    let _ = statement.next()?;
+                    Ok(())
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The tags of the row key `key`, in order.
+    fn fetch_tags(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        self.with_statement(
+            "fetch_tags",
+            |tables| {
+                format!(
+                    r#"SELECT tag FROM "{}" WHERE key = :key ORDER BY tag"#,
+                    tables.tags
+                )
+            },
+            |statement| {
+                statement.bind((":key", key))?;
+                let mut tags = Vec::new();
+                while let sqlite::State::Row = statement.next()? {
+                    tags.push(statement.read::<String, _>("tag")?);
+                }
+                Ok(tags)
+            },
+        )
+    }
+
+    /// Record that the entry under `key` read the entries under `deps`
+    /// while it was computed, replacing what it read before.
+    pub(crate) fn depend(&self, key: &str, deps: &[String]) -> Result<(), StoreError> {
+        let deps: Vec<String> = deps
+            .iter()
+            .filter(|dep| *dep != key)
+            .map(|dep| self.row_key(dep).into_owned())
+            .collect();
+        self.write_deps(&self.row_key(key), &deps)
+    }
+
+    /// Replace the edges from the row key `key` with edges to the row keys
+    /// `deps`.
+    fn write_deps(&self, key: &str, deps: &[String]) -> Result<(), StoreError> {
+        self.with_statement(
+            "clear_deps",
+            |tables| format!(r#"DELETE FROM "{}" WHERE parent = :key"#, tables.deps),
+            |statement| {
+                statement.bind((":key", key))?;
+                let _ = statement.next()?;
+                Ok(())
+            },
+        )?;
+        for dep in deps {
+            self.with_statement(
+                "add_dep",
+                |tables| {
+                    format!(
+                        r#"INSERT OR IGNORE INTO "{}" (parent, child) VALUES (:key, :dep)"#,
+                        tables.deps
+                    )
+                },
+                |statement| {
+                    statement.bind(&[(":key", key), (":dep", dep.as_str())][..])?;
+                    let _ = statement.next()?;
+                    Ok(())
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The keys the entry under `key` read while it was computed, sorted.
+    pub(crate) fn dependencies(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        if self.hashes_keys() {
+            return HashedKeysSnafu.fail();
+        }
+        self.with_statement(
+            "dependencies",
+            |tables| {
+                format!(
+                    r#"SELECT child FROM "{}" WHERE parent = :key ORDER BY child"#,
+                    tables.deps
+                )
+            },
+            |statement| {
+                statement.bind((":key", key))?;
+                let mut keys = Vec::new();
+                while let sqlite::State::Row = statement.next()? {
+                    keys.push(statement.read::<String, _>("child")?);
+                }
+                Ok(keys)
+            },
+        )
+    }
+
+    /// The keys of the entries that read the entry under `key` while they
+    /// were computed, sorted.
+    pub(crate) fn dependents(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        if self.hashes_keys() {
+            return HashedKeysSnafu.fail();
+        }
+        self.with_statement(
+            "dependents",
+            |tables| {
+                format!(
+                    r#"SELECT parent FROM "{}" WHERE child = :key ORDER BY parent"#,
+                    tables.deps
+                )
+            },
+            |statement| {
+                statement.bind((":key", key))?;
+                let mut keys = Vec::new();
+                while let sqlite::State::Row = statement.next()? {
+                    keys.push(statement.read::<String, _>("parent")?);
+                }
+                Ok(keys)
+            },
+        )
+    }
+
+    /// Delete every row tagged `tag`, returning how many were deleted.
+    pub(crate) fn invalidate_tag(&self, tag: &str) -> Result<u64, StoreError> {
+        let keys = self.with_statement(
+            "tagged",
+            |tables| format!(r#"SELECT key FROM "{}" WHERE tag = :tag"#, tables.tags),
+            |statement| {
+                statement.bind((":tag", tag))?;
+                let mut keys = Vec::new();
+                while let sqlite::State::Row = statement.next()? {
+                    keys.push(statement.read::<String, _>("key")?);
+                }
+                Ok(keys)
+            },
+        )?;
+        self.savepoint(|| {
+            for key in &keys {
+                self.delete_row(key)?;
+            }
+            Ok(keys.len() as u64)
+        })
+    }
+
+    fn has_blob(&self, hash: &str) -> Result<bool, StoreError> {
+        self.with_statement(
+            "has_blob",
+            |tables| format!(r#"SELECT 1 FROM "{}" WHERE hash = :hash"#, tables.blobs),
+            |statement| {
+                statement.bind((":hash", hash))?;
+                Ok(statement.next()? == sqlite::State::Row)
+            },
+        )
+    }
+
+    fn insert_blob(
+        &self,
+        hash: &str,
+        value: &[u8],
+        compression: Option<&str>,
+        key_id: Option<&str>,
+    ) -> Result<(), StoreError> {
+        self.with_statement(
+            "insert_blob",
+            |tables| {
+                format!(
+                    r#"INSERT OR IGNORE INTO "{}" (hash, value, compression, key_id)
+                    VALUES (:hash, :value, :compression, :key_id)"#,
+                    tables.blobs
+                )
+            },
+            |statement| {
+                statement.bind((":hash", hash))?;
+                statement.bind((":value", value))?;
+                statement.bind((":compression", compression))?;
+                statement.bind((":key_id", key_id))?;
+                let _ = statement.next()?;
+                Ok(())
+            },
+        )
+    }
+
+    /// Delete blobs that no row references. Returns how many were deleted.
+    pub(crate) fn gc_blobs(&self) -> Result<u64, StoreError> {
+        self.connection.execute(format!(
+            r#"DELETE FROM "{0}" WHERE NOT EXISTS (
+                SELECT 1 FROM "{1}" v WHERE v.blob = "{0}".hash
+            )"#,
+            self.tables.blobs, self.tables.values
+        ))?;
+        Ok(self.connection.change_count() as u64)
+    }
+
+    /// Move every value not sealed with the keyring's current key, or not
+    /// sealed at all, to the current key. Returns how many rows and blobs
+    /// were rewritten.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn reencrypt(&self) -> Result<u64, StoreError> {
+        let Some(keyring) = self.keyring else {
+            return Ok(0);
+        };
+        let current = keyring.current_id();
+        let mut rewritten = 0;
+        // Rows store their own value; blobs are addressed by hash.
+        for (table, id, filter) in [
+            (&self.tables.values, "key", "blob IS NULL AND "),
+            (&self.tables.blobs, "hash", ""),
+        ] {
+            let select = format!(
+                r#"SELECT {id} AS id, value, key_id FROM "{table}"
+                WHERE {filter}(key_id IS NULL OR key_id != :current) LIMIT 256"#
+            );
+            let update = format!(
+                r#"UPDATE "{table}" SET value = :value, key_id = :current WHERE {id} = :id"#
+            );
+            // Rewritten rows stop matching, so each page is the next one.
+            loop {
+                let mut statement = self.connection.prepare(&select)?;
+                statement.bind((":current", current))?;
+                let mut page = Vec::new();
+                while let sqlite::State::Row = statement.next()? {
+                    page.push((
+                        statement.read::<String, _>("id")?,
+                        statement.read::<Vec<u8>, _>("value")?,
+                        statement.read::<Option<String>, _>("key_id")?,
+                    ));
+                }
+                if page.is_empty() {
+                    break;
+                }
+                let mut statement = self.connection.prepare(&update)?;
+                for (id, value, key_id) in page {
+                    let sealed = keyring.seal(&id, &self.open(key_id, &id, value)?)?;
+                    statement.reset()?;
+                    statement.bind((":id", id.as_str()))?;
+                    statement.bind((":value", sealed.as_slice()))?;
+                    statement.bind((":current", current))?;
+                    let _ = statement.next()?;
+                    rewritten += 1;
+                }
+            }
+        }
+        Ok(rewritten)
+    }
+
+    /// Add `hits`, as collected by [`Db::record_hit`], to their rows.
+    fn record_hits(&self, hits: &HashMap<String, (u64, i64)>) -> Result<(), StoreError> {
+        for (key, &(count, at)) in hits {
+            let key = &*self.row_key(key);
+            self.with_statement(
+                "record_hit",
+                |tables| {
+                    format!(
+                        r#"UPDATE "{}" SET hit_count = hit_count + :count,
+                            last_accessed_at = max(coalesce(last_accessed_at, 0), :at)
+                        WHERE key = :key"#,
+                        tables.values
+                    )
+                },
+                |statement| {
+                    statement.bind((":key", key))?;
+                    statement.bind((":count", count as i64))?;
+                    statement.bind((":at", at))?;
+                    let _ = statement.next()?;
+                    Ok(())
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn entry_info(&self, key: &str) -> Result<Option<EntryInfo>, StoreError> {
+        let stamps = self.fetch_stamps(&self.row_key(key))?;
+        Ok(stamps.map(EntryInfo::from))
+    }
+
+    /// The stamps of the row stored under the row key `key`.
+    fn fetch_stamps(&self, key: &str) -> Result<Option<Stamps>, StoreError> {
+        self.with_statement(
+            "fetch_stamps",
+            |tables| {
+                format!(
+                    r#"SELECT created_at, expires_at, last_accessed_at, hit_count,
+                        compute_duration, potency_version, pinned
+                    FROM "{}" WHERE key = :key"#,
+                    tables.values
+                )
+            },
+            |statement| {
+                statement.bind((":key", key))?;
+                match statement.next()? {
+                    sqlite::State::Row => Ok(Some(Stamps::read(statement)?)),
+                    sqlite::State::Done => Ok(None),
+                }
+            },
+        )?
+        .map(|stamps| {
+            Ok(Stamps {
+                tags: self.fetch_tags(key)?,
+                ..stamps
+            })
+        })
+        .transpose()
+    }
+
+    /// Up to `limit` keys after `after`, in order, with their
+    /// [`EntryInfo`]. Only keys in the namespace `prefix` are listed, i.e.
+    /// `prefix` itself and keys starting with `"{prefix},"`; an empty
+    /// `prefix` lists every key.
+    pub(crate) fn list_entries(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, EntryInfo)>, StoreError> {
+        let stamps = self.list_stamps(prefix, after, limit)?;
+        Ok(stamps
+            .into_iter()
+            .map(|(key, stamps)| (key, stamps.into()))
+            .collect())
+    }
+
+    /// Like [`Conn::list_entries`], but with each key's row.
+    pub(crate) fn list_values(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Row)>, StoreError> {
+        let mut values = Vec::new();
+        for (key, _) in self.list_stamps(prefix, after, limit)? {
+            // Listed keys are row keys already, so skip hashing them.
+            if let Some(row) = self.fetch_row(&key)? {
+                values.push((key, row));
+            }
+        }
+        Ok(values)
+    }
+
+    /// Like [`Conn::list_entries`], but as [`Record`]s for
+    /// [`Store::export`][crate::Store::export].
+    pub(crate) fn list_records(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Record)>, StoreError> {
+        let mut records = Vec::new();
+        for (key, stamps) in self.list_stamps(prefix, after, limit)? {
+            if let Some(row) = self.fetch_row(&key)? {
+                let record = Record::new(key.clone(), self.hashes_keys(), row, stamps)?;
+                records.push((key, record));
+            }
+        }
+        Ok(records)
+    }
+
+    /// The entry stored under `key` as a [`Record`], for the remote tier.
+    pub(crate) fn record(&self, key: &str) -> Result<Option<Record>, StoreError> {
+        let key = self.row_key(key);
+        let (Some(row), Some(stamps)) = (self.fetch_row(&key)?, self.fetch_stamps(&key)?) else {
+            return Ok(None);
+        };
+        Record::new(key.into_owned(), self.hashes_keys(), row, stamps).map(Some)
+    }
+
+    fn list_stamps(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Stamps)>, StoreError> {
+        if !prefix.is_empty() && self.hashes_keys() {
+            return HashedKeysSnafu.fail();
+        }
+        self.with_statement(
+            "list_stamps",
+            |tables| {
+                format!(
+                    r#"SELECT key, created_at, expires_at, last_accessed_at, hit_count,
+                        compute_duration, potency_version, pinned
+                    FROM "{}"
+                    WHERE (:after IS NULL OR key > :after)
+                        AND (:prefix = '' OR key = :prefix
+                            OR substr(key, 1, length(:prefix) + 1) = :prefix || ',')
+                    ORDER BY key LIMIT :limit"#,
+                    tables.values
+                )
+            },
+            |statement| {
+                statement.bind((":after", after))?;
+                statement.bind((":prefix", prefix))?;
+                statement.bind((":limit", limit as i64))?;
+                let mut entries = Vec::new();
+                while let sqlite::State::Row = statement.next()? {
+                    let key = statement.read::<String, _>("key")?;
+                    entries.push((key, Stamps::read(statement)?));
+                }
+                Ok(entries)
+            },
+        )?
+        .into_iter()
+        .map(|(key, stamps)| {
+            let tags = self.fetch_tags(&key)?;
+            Ok((key, Stamps { tags, ..stamps }))
+        })
+        .collect()
+    }
+
+    /// Up to `limit` changes after the change numbered `after`, in order,
+    /// each with its sequence number. See
+    /// [`Store::changes_since`][crate::Store::changes_since].
+    pub(crate) fn list_changes(
+        &self,
+        after: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Record)>, StoreError> {
+        let changes = self.with_statement(
+            "list_changes",
+            |tables| {
+                format!(
+                    r#"SELECT seq, key, deleted_at FROM "{}" WHERE seq > :after
+                    ORDER BY seq LIMIT :limit"#,
+                    tables.changes
+                )
+            },
+            |statement| {
+                statement.bind((":after", after as i64))?;
+                statement.bind((":limit", limit as i64))?;
+                let mut changes = Vec::new();
+                while let sqlite::State::Row = statement.next()? {
+                    changes.push((
+                        statement.read::<i64, _>("seq")? as u64,
+                        statement.read::<String, _>("key")?,
+                        statement.read::<Option<i64>, _>("deleted_at")?,
+                    ));
+                }
+                Ok(changes)
+            },
+        )?;
+        let mut records = Vec::new();
+        for (seq, key, deleted_at) in changes {
+            let record = match deleted_at {
+                Some(at) => Record::deleted(key, self.hashes_keys(), at),
+                None => {
+                    // The trigger logs every write, so the row is there.
+                    let (Some(row), Some(stamps)) =
+                        (self.fetch_row(&key)?, self.fetch_stamps(&key)?)
+                    else {
+                        continue;
+                    };
+                    Record::new(key, self.hashes_keys(), row, stamps)?
+                }
+            };
+            records.push((seq, record));
+        }
+        Ok(records)
+    }
+
+    /// Write `entries`, read by [`Store::import`][crate::Store::import],
+    /// all or nothing.
+    pub(crate) fn import(
+        &self,
+        entries: Vec<Imported>,
+        encoding: Encoding,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport, StoreError> {
+        self.savepoint(|| {
+            let mut report = ImportReport::default();
+            self.import_into(entries, encoding, policy, &mut report)?;
+            Ok(report)
+        })
+    }
+
+    /// Import the batches of entries received from `batches` in one
+    /// savepoint, each as it arrives, until an empty batch marks the end.
+    /// If `batches` closes before that, nothing is imported.
+    pub(crate) fn import_batches(
+        &self,
+        batches: &async_channel::Receiver<Vec<Imported>>,
+        encoding: Encoding,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport, StoreError> {
+        self.savepoint(|| {
+            let mut report = ImportReport::default();
+            loop {
+                // Closed only when the import failed on the sending side or
+                // was dropped, so the error is never seen.
+                let batch = batches.recv_blocking().ok().context(DatabaseThreadSnafu)?;
+                if batch.is_empty() {
+                    return Ok(report);
+                }
+                self.import_into(batch, encoding, policy, &mut report)?;
+            }
+        })
+    }
+
+    /// Import the entries under `prefix` into this same database, in one
+    /// savepoint, a page at a time.
+    pub(crate) fn import_own(
+        &self,
+        prefix: &str,
+        encoding: Encoding,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport, StoreError> {
+        self.savepoint(|| {
+            let mut report = ImportReport::default();
+            let mut after = None;
+            loop {
+                let records = self.list_records(prefix, after.as_deref(), SCAN_PAGE)?;
+                let done = records.len() < SCAN_PAGE;
+                after = records.last().map(|(key, _)| key.clone());
+                let entries = records
+                    .into_iter()
+                    .map(|(_, record)| record.into_imported(None))
+                    .collect::<Result<_, _>>()?;
+                self.import_into(entries, encoding, policy, &mut report)?;
+                if done {
+                    return Ok(report);
+                }
+            }
+        })
+    }
+
+    /// Import `entries`, counting them in `report`. Not a savepoint of its
+    /// own; see [`Conn::import_batches`].
+    fn import_into(
+        &self,
+        entries: Vec<Imported>,
+        encoding: Encoding,
+        policy: ConflictPolicy,
+        report: &mut ImportReport,
+    ) -> Result<(), StoreError> {
+        for entry in entries {
+            let key = match entry.hashed {
+                true => entry.key.as_str().into(),
+                false => self.row_key(&entry.key),
+            };
+            match self.fetch_stamps(&key)? {
+                Some(existing) => {
+                    let replace = match policy {
+                        ConflictPolicy::SkipExisting => false,
+                        ConflictPolicy::Overwrite => true,
+                        ConflictPolicy::LastWriterWins => {
+                            entry.stamps.created_at > existing.created_at
+                        }
+                        ConflictPolicy::Fail => {
+                            return ImportConflictSnafu { key: entry.key }.fail();
+                        }
+                    };
+                    if !replace {
+                        report.skipped += 1;
+                        continue;
+                    }
+                    report.overwritten += 1;
+                }
+                // Nothing to delete.
+                None if entry.value.is_none() => continue,
+                None => {}
+            }
+            match entry.value {
+                Some((codec, bytes)) => {
+                    let encoding = Encoding { codec, ..encoding };
+                    self.write_row(&key, encoding, &bytes, &entry.stamps)?;
+                    report.imported += 1;
+                }
+                None => {
+                    self.delete_row(&key)?;
+                    report.deleted += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_value(&self, key: &str) -> Result<(), StoreError> {
+        self.delete_row(&self.row_key(key))
+    }
+
+    /// Delete the row stored under the row key `key`.
+    fn delete_row(&self, key: &str) -> Result<(), StoreError> {
+        self.with_statement(
+            "delete_value",
+            |tables| format!(r#"DELETE FROM "{}" WHERE key = :key"#, tables.values),
+            |statement| {
+                statement.bind((":key", key))?;
+                let _ = statement.next()?;
+                Ok(())
+            },
+        )?;
+        self.set_pending(key, None);
+        Ok(())
+    }
+
+    pub(crate) fn fetch_panics(&self, key: &str) -> Result<u32, StoreError> {
+        let key = &*self.row_key(key);
+        self.with_statement(
+            "fetch_panics",
+            |tables| {
+                format!(
+                    r#"SELECT panics FROM "{}" WHERE key = :key"#,
+                    tables.failures
+                )
+            },
+            |statement| {
+                statement.bind((":key", key))?;
+                match statement.next()? {
+                    sqlite::State::Row => Ok(statement.read::<i64, _>("panics")? as u32),
+                    sqlite::State::Done => Ok(0),
+                }
+            },
+        )
+    }
+
+    pub(crate) fn record_panic(&self, key: &str, message: &str) -> Result<(), StoreError> {
+        let key = &*self.row_key(key);
+        self.with_statement(
+            "record_panic",
+            |tables| {
+                format!(
+                    r#"INSERT INTO "{}" (key, panics, message) VALUES (:key, 1, :message)
+                    ON CONFLICT(key) DO UPDATE SET panics = panics + 1, message = excluded.message"#,
+                    tables.failures
+                )
+            },
+            |statement| {
+                statement.bind(&[(":key", key), (":message", message)][..])?;
+                let _ = statement.next()?;
+                Ok(())
+            },
+        )
+    }
+
+    /// Find the unpinned entries under the namespace `prefix` that `roots`
+    /// don't cover and, unless `dry_run`, delete them along with their
+    /// recorded panics and the blobs no entry uses any more.
+    pub(crate) fn collect_garbage(
+        &self,
+        prefix: &str,
+        roots: &Roots,
+        dry_run: bool,
+    ) -> Result<GcReport, StoreError> {
+        if self.hashes_keys() {
+            return HashedKeysSnafu.fail();
+        }
+        let under_prefix = "(:prefix = '' OR key = :prefix
+            OR substr(key, 1, length(:prefix) + 1) = :prefix || ',')";
+        let mut report = GcReport::default();
+        let mut garbage = Vec::new();
+        let mut statement = self.connection.prepare(format!(
+            r#"SELECT key, length(CAST(v.value AS BLOB)) + coalesce(length(b.value), 0) AS bytes
+            FROM "{}" v LEFT JOIN "{}" b ON b.hash = v.blob
+            WHERE {under_prefix} AND NOT pinned"#,
+            self.tables.values, self.tables.blobs
+        ))?;
+        statement.bind((":prefix", prefix))?;
+        while let sqlite::State::Row = statement.next()? {
+            let key = statement.read::<String, _>("key")?;
+            if !roots.contains(&key) {
+                report.add(&key, statement.read::<i64, _>("bytes")? as u64);
+                garbage.push(key);
+            }
+        }
+        if dry_run {
+            return Ok(report);
+        }
+        let mut statement = self.connection.prepare(format!(
+            r#"SELECT key FROM "{}" WHERE {under_prefix}"#,
+            self.tables.failures
+        ))?;
+        statement.bind((":prefix", prefix))?;
+        let mut failures = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            let key = statement.read::<String, _>("key")?;
+            if !roots.contains(&key) {
+                failures.push(key);
+            }
+        }
+        self.savepoint(|| {
+            for key in &garbage {
+                self.delete_row(key)?;
+            }
+            for key in &failures {
+                self.clear_panics(key)?;
+            }
+            self.gc_blobs()
+        })?;
+        Ok(report)
+    }
+
+    pub(crate) fn clear_panics(&self, key: &str) -> Result<(), StoreError> {
+        let key = &*self.row_key(key);
+        self.with_statement(
+            "clear_panics",
+            |tables| format!(r#"DELETE FROM "{}" WHERE key = :key"#, tables.failures),
+            |statement| {
+                statement.bind((":key", key))?;
+                let _ = statement.next()?;
+                Ok(())
+            },
+        )
+    }
+
+    pub(crate) fn stats(&self) -> Result<Stats, StoreError> {
+        self.with_statement(
+            "stats",
+            |tables| {
+                format!(
+                    r#"SELECT count(*) AS entries,
+                        count(compression) AS compressed_entries,
+                        coalesce(sum(coalesce(raw_size, length(CAST(value AS BLOB)))), 0) AS raw_bytes,
+                        coalesce(sum(length(CAST(value AS BLOB))), 0)
+                            + (SELECT coalesce(sum(length(value)), 0) FROM "{1}") AS stored_bytes,
+                        (SELECT count(*) FROM "{1}") AS blobs,
+                        (SELECT count(*) FROM "{1}" WHERE compression IS NOT NULL)
+                            AS compressed_blobs
+                    FROM "{0}""#,
+                    tables.values, tables.blobs
+                )
+            },
+            |statement| {
+                statement.next()?;
+                let read = |column| statement.read::<i64, _>(column).map(|n| n as u64);
+                Ok(Stats {
+                    entries: read("entries")?,
+                    compressed_entries: read("compressed_entries")?
+                        + read("compressed_blobs")?,
+                    raw_bytes: read("raw_bytes")?,
+                    stored_bytes: read("stored_bytes")?,
+                    blobs: read("blobs")?,
+                })
+            },
+        )
+    }
+}
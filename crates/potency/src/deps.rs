@@ -0,0 +1,107 @@
+//! Tracking which entries a computation reads, for
+//! [`Store::dependencies`][crate::Store::dependencies] and
+//! [`Store::dependents`][crate::Store::dependents].
+//!
+//! While a function runs for [`Store::fetch_or_else`][crate::Store], its
+//! [`Computation`] is the thread's current one: set around the call that
+//! creates its future and around every poll of that future, so it follows
+//! the function through `.await`s whichever executor runs it. A durable
+//! call made meanwhile, from the function itself or from anything it
+//! awaits, records its key in the current computation. Work the function
+//! spawns onto other tasks isn't tracked.
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Rc<Computation>>> = const { RefCell::new(None) };
+}
+
+/// A function running to produce one entry.
+pub(crate) struct Computation {
+    /// Which database the entry is stored in, so that calls into another
+    /// store aren't recorded.
+    db: usize,
+    /// Full keys read so far, without repeats.
+    deps: RefCell<Vec<String>>,
+}
+
+impl Computation {
+    pub(crate) fn new(db: usize) -> Rc<Self> {
+        Rc::new(Computation {
+            db,
+            deps: RefCell::default(),
+        })
+    }
+
+    /// The keys read, in the order they were first read.
+    pub(crate) fn take(&self) -> Vec<String> {
+        self.deps.take()
+    }
+}
+
+/// Note that the current computation, if any, read `key` from the database
+/// `db`.
+pub(crate) fn record(db: usize, key: &str) {
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        let Some(computation) = current.as_ref().filter(|c| c.db == db) else {
+            return;
+        };
+        let mut deps = computation.deps.borrow_mut();
+        if !deps.iter().any(|dep| dep == key) {
+            deps.push(key.to_owned());
+        }
+    })
+}
+
+/// Call `f` with `computation` as the current computation.
+fn enter<R>(computation: &Rc<Computation>, f: impl FnOnce() -> R) -> R {
+    /// Puts the previous computation back, even if `f` panics.
+    struct Restore(Option<Rc<Computation>>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CURRENT.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+
+    let previous = CURRENT.with(|current| current.borrow_mut().replace(computation.clone()));
+    let _restore = Restore(previous);
+    f()
+}
+
+/// `f`, run as `computation`: both the call and the future it returns.
+pub(crate) fn track<Fut: Future>(
+    computation: Rc<Computation>,
+    f: impl FnOnce() -> Fut,
+) -> impl FnOnce() -> Tracked<Fut> {
+    move || {
+        let inner = enter(&computation, f);
+        Tracked {
+            computation,
+            inner: Box::pin(inner),
+        }
+    }
+}
+
+/// A future polled as a [`Computation`]; see [`track`].
+pub(crate) struct Tracked<Fut> {
+    computation: Rc<Computation>,
+    inner: Pin<Box<Fut>>,
+}
+
+impl<Fut: Future> Future for Tracked<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        enter(&this.computation, || this.inner.as_mut().poll(cx))
+    }
+}
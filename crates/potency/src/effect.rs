@@ -221,7 +221,7 @@ mod test {
     use std::sync::atomic::{AtomicU32, Ordering};
     use std::sync::Arc;
 
-    use crate::{EffectError, Store};
+    use crate::{EffectError, Mode, Store};
 
     use super::*;
 
@@ -328,6 +328,30 @@ mod test {
         });
     }
 
+    #[test]
+    fn tagged_manifest_is_invalidated() {
+        smol::block_on(async {
+            let tmp = TmpDir::new("tagged");
+            let out = tmp.path().join("frames_up");
+            let calls = Arc::new(AtomicU32::new(0));
+            let store = open_store().await;
+            let run = || {
+                store
+                    .effect(fs_effect(&out, make_produce(calls.clone(), 2)))
+                    .param("k")
+                    .tag("frames")
+                    .run()
+            };
+
+            run().await.unwrap();
+            run().await.unwrap();
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+            assert_eq!(store.invalidate_tag("frames").await.unwrap(), 1);
+            run().await.unwrap();
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+        });
+    }
+
     #[test]
     fn effect_partial_output_count_mismatch_recomputes() {
         smol::block_on(async {
@@ -380,6 +404,7 @@ mod test {
                     .run()
                     .await
                     .unwrap();
+                store.close().await.unwrap();
             }
             assert_eq!(calls.load(Ordering::SeqCst), 1);
 
@@ -394,6 +419,7 @@ mod test {
                     .await
                     .unwrap();
                 assert_eq!(m.file_count, 6);
+                store.close().await.unwrap();
             }
             assert_eq!(
                 calls.load(Ordering::SeqCst),
@@ -412,6 +438,7 @@ mod test {
                     .run()
                     .await
                     .unwrap();
+                store.close().await.unwrap();
             }
             assert_eq!(
                 calls.load(Ordering::SeqCst),
@@ -452,6 +479,52 @@ mod test {
         });
     }
 
+    #[test]
+    fn effect_cache_only_verifies_without_producing() {
+        smol::block_on(async {
+            let tmp = TmpDir::new("cache-only");
+            let out = tmp.path().join("frames_up");
+            let calls = Arc::new(AtomicU32::new(0));
+            let store = open_store().await;
+            let cache_only = store.clone().with_mode(Mode::CacheOnly);
+
+            let result = cache_only
+                .effect(fs_effect(&out, make_produce(calls.clone(), 2)))
+                .param("k")
+                .run()
+                .await;
+            assert!(matches!(
+                result,
+                Err(EffectError::Store(StoreError::NotCached { .. }))
+            ));
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+            store
+                .effect(fs_effect(&out, make_produce(calls.clone(), 2)))
+                .param("k")
+                .run()
+                .await
+                .unwrap();
+            let m = cache_only
+                .effect(fs_effect(&out, make_produce(calls.clone(), 2)))
+                .param("k")
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(m.file_count, 2);
+            assert_eq!(calls.load(Ordering::SeqCst), 1, "verified hit, no produce");
+
+            std::fs::remove_dir_all(&out).unwrap();
+            let result = cache_only
+                .effect(fs_effect(&out, make_produce(calls.clone(), 2)))
+                .param("k")
+                .run()
+                .await;
+            assert!(result.is_err(), "stale entry is a miss in cache-only mode");
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+        });
+    }
+
     #[test]
     fn effect_atomic_commit_no_partial_final_on_produce_failure() {
         smol::block_on(async {
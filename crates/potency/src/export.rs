@@ -0,0 +1,256 @@
+//! The JSON Lines format of [`Store::export`][crate::Store::export] and
+//! [`Store::import`][crate::Store::import].
+//!
+//! Each line is one entry: its full key, its codec, its value and its
+//! bookkeeping. Values written with [`Codec::Json`] are embedded as JSON,
+//! so small exports read (and diff) well as fixtures; values in any other
+//! codec are hex strings. Values are exported decompressed and decrypted,
+//! and the importing store compresses and encrypts them by its own options.
+//!
+//! ```json
+//! {"key":"squares,12","codec":"json","value":144,"created_at":1750000000000,"hit_count":3}
+//! ```
+
+use crate::{
+    db::{Keyring, Row, Stamps},
+    Codec, InvalidImportSnafu, InvalidRecordSnafu, StoreError, UnknownKeySnafu,
+};
+
+/// What [`Store::import`][crate::Store::import] does with an entry whose
+/// key is already in the store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the store's entry. The default.
+    #[default]
+    SkipExisting,
+    /// Replace the store's entry with the imported one.
+    Overwrite,
+    /// Keep whichever entry was stored last, by its creation time. Ties
+    /// keep the store's entry.
+    LastWriterWins,
+    /// Abort the import with [`StoreError::ImportConflict`], leaving the
+    /// store as it was.
+    Fail,
+}
+
+/// What [`Store::import`][crate::Store::import] did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ImportReport {
+    /// Entries written, including overwrites.
+    pub imported: u64,
+    /// Entries deleted, from the deletions in
+    /// [`Store::changes_since`][crate::Store::changes_since].
+    pub deleted: u64,
+    /// Of those written or deleted, entries that replaced or removed one
+    /// already in the store.
+    pub overwritten: u64,
+    /// Entries left out because their key was already in the store.
+    pub skipped: u64,
+}
+
+impl ImportReport {
+    /// Count `other` in too, for an import done in parts.
+    pub(crate) fn add(&mut self, other: ImportReport) {
+        self.imported += other.imported;
+        self.deleted += other.deleted;
+        self.overwritten += other.overwritten;
+        self.skipped += other.skipped;
+    }
+}
+
+/// One line of an export.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Record {
+    key: String,
+    /// Whether `key` is a keyed hash; see
+    /// [`Keyring::hash_keys`][crate::Keyring::hash_keys].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    hashed: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    codec: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "hex")]
+    bytes: Option<Vec<u8>>,
+    /// Set instead of a value when the entry was deleted, to when.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<i64>,
+    /// The key `bytes` are sealed with, if they are; see [`Record::seal`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    #[serde(flatten)]
+    stamps: Stamps,
+}
+
+/// An entry read from an export, ready to be written.
+pub(crate) struct Imported {
+    pub(crate) key: String,
+    pub(crate) hashed: bool,
+    /// The value and its codec, or `None` to delete the entry. A deletion's
+    /// `stamps.created_at` is when it happened.
+    pub(crate) value: Option<(Codec, Vec<u8>)>,
+    pub(crate) stamps: Stamps,
+}
+
+impl Record {
+    pub(crate) fn new(
+        key: String,
+        hashed: bool,
+        row: Row,
+        stamps: Stamps,
+    ) -> Result<Self, StoreError> {
+        let (value, bytes) = if row.codec == Codec::Json {
+            (Some(serde_json::from_slice(&row.bytes)?), None)
+        } else {
+            (None, Some(row.bytes))
+        };
+        Ok(Record {
+            key,
+            hashed,
+            codec: row.codec.tag().to_owned(),
+            value,
+            bytes,
+            deleted_at: None,
+            key_id: None,
+            stamps,
+        })
+    }
+
+    /// The row key of the entry.
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The record of `key` having been deleted at `at`.
+    pub(crate) fn deleted(key: String, hashed: bool, at: i64) -> Self {
+        Record {
+            key,
+            hashed,
+            codec: String::new(),
+            value: None,
+            bytes: None,
+            deleted_at: Some(at),
+            key_id: None,
+            stamps: Stamps::default(),
+        }
+    }
+
+    /// Whether the value is sealed.
+    pub(crate) fn is_sealed(&self) -> bool {
+        self.key_id.is_some()
+    }
+
+    /// This record with its value sealed by `keyring` and bound to its key,
+    /// for the [`remote`][crate::remote] tier to keep.
+    pub(crate) fn seal(mut self, keyring: &Keyring) -> Result<Self, StoreError> {
+        let bytes = match (self.value.take(), self.bytes.take()) {
+            (Some(value), _) => serde_json::to_vec(&value)?,
+            (None, Some(bytes)) => bytes,
+            // A deletion has nothing to seal.
+            (None, None) => return Ok(self),
+        };
+        self.bytes = Some(keyring.seal(&self.key, &bytes)?);
+        self.key_id = Some(keyring.current_id().to_owned());
+        Ok(self)
+    }
+
+    /// This record with a value sealed by [`Record::seal`] opened by
+    /// `keyring`.
+    pub(crate) fn open(mut self, keyring: Option<&Keyring>) -> Result<Self, StoreError> {
+        let Some(key_id) = self.key_id.take() else {
+            return Ok(self);
+        };
+        let Some(keyring) = keyring else {
+            return UnknownKeySnafu { key_id }.fail();
+        };
+        let sealed = self.bytes.take().unwrap_or_default();
+        self.bytes = Some(keyring.open(&key_id, &self.key, &sealed)?);
+        Ok(self)
+    }
+
+    /// Parse line number `line` of an export.
+    pub(crate) fn parse(line: u64, text: &str) -> Result<Imported, StoreError> {
+        let record: Record = serde_json::from_str(text).map_err(|e| {
+            InvalidImportSnafu {
+                line,
+                message: e.to_string(),
+            }
+            .build()
+        })?;
+        record.into_imported(Some(line))
+    }
+
+    /// The entry this record describes, which was line number `line` of an
+    /// export if it was read from one.
+    pub(crate) fn into_imported(self, line: Option<u64>) -> Result<Imported, StoreError> {
+        if self.is_sealed() {
+            return Err(invalid(line, format!("{:?} is sealed", self.key)));
+        }
+        let mut stamps = self.stamps;
+        let value = match (self.value, self.bytes, self.deleted_at) {
+            (None, None, Some(at)) => {
+                stamps.created_at = at;
+                None
+            }
+            (Some(value), None, None) => {
+                let codec = Codec::from_tag(&self.codec)?;
+                if codec != Codec::Json {
+                    let message = format!("{:?} has a JSON value but codec {codec:?}", self.key);
+                    return Err(invalid(line, message));
+                }
+                Some((codec, serde_json::to_vec(&value)?))
+            }
+            (None, Some(bytes), None) => Some((Codec::from_tag(&self.codec)?, bytes)),
+            _ => {
+                let message = format!(
+                    "{:?} needs exactly one of \"value\", \"bytes\" or \"deleted_at\"",
+                    self.key
+                );
+                return Err(invalid(line, message));
+            }
+        };
+        Ok(Imported {
+            key: self.key,
+            hashed: self.hashed,
+            value,
+            stamps,
+        })
+    }
+}
+
+/// The error for an invalid record, at line number `line` of an export if
+/// it was read from one.
+fn invalid(line: Option<u64>, message: String) -> StoreError {
+    match line {
+        Some(line) => InvalidImportSnafu { line, message }.build(),
+        None => InvalidRecordSnafu { message }.build(),
+    }
+}
+
+/// Bytes as a lowercase hex string.
+mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let bytes = bytes.as_deref().unwrap_or_default();
+        let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(D::Error::custom("invalid hex"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+}
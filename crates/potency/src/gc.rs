@@ -0,0 +1,86 @@
+//! Deleting the entries of namespaces no longer in use, for
+//! [`Store::gc`][crate::Store::gc].
+
+use std::collections::BTreeMap;
+
+/// The key prefixes whose entries [`Store::gc`][crate::Store::gc] keeps.
+///
+/// A prefix is a whole number of key segments: `"users"` keeps
+/// `"users,42"` but not `"users2,42"`.
+///
+/// ```rust,no_run
+/// # async fn doc(store: potency::Store) -> Result<(), potency::StoreError> {
+/// use potency::Roots;
+///
+/// let roots = Roots::new().durable().prefix("reports,2024");
+/// let report = store.gc_dry_run(&roots).await?;
+/// println!("gc would free {} bytes", report.total.bytes);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Roots {
+    pub(crate) prefixes: Vec<String>,
+}
+
+impl Roots {
+    /// No roots: every entry is garbage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the entries under `prefix`, a namespace or a full key
+    /// prefix with segments joined by `","`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefixes.push(prefix.into());
+        self
+    }
+
+    /// Keep the namespace of every [`DurableFn`][crate::DurableFn] linked
+    /// into the program.
+    pub fn durable(mut self) -> Self {
+        self.prefixes
+            .extend(crate::durable_fns().map(|f| f.namespace.to_owned()));
+        self
+    }
+
+    /// Whether `key` is under one of the roots.
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.prefixes.iter().any(|prefix| {
+            key.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(','))
+        })
+    }
+}
+
+/// What [`Store::gc`][crate::Store::gc] deleted, or
+/// [`Store::gc_dry_run`][crate::Store::gc_dry_run] would delete.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Everything unreachable, across namespaces.
+    pub total: Garbage,
+    /// The same, by the first segment of the keys.
+    pub by_namespace: BTreeMap<String, Garbage>,
+}
+
+/// A count of unreachable entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Garbage {
+    pub entries: u64,
+    /// Size of their values as stored. A blob shared by several entries
+    /// counts for each of them.
+    pub bytes: u64,
+}
+
+impl GcReport {
+    pub(crate) fn add(&mut self, key: &str, bytes: u64) {
+        let namespace = key.split(',').next().unwrap_or_default();
+        for garbage in [
+            &mut self.total,
+            self.by_namespace.entry(namespace.to_owned()).or_default(),
+        ] {
+            garbage.entries += 1;
+            garbage.bytes += bytes;
+        }
+    }
+}
@@ -56,6 +56,41 @@ pub use key::*;
 mod tuple;
 pub use tuple::*;
 
+mod options;
+pub use options::*;
+
+mod codec;
+use codec::Encoding;
+pub use codec::{Codec, Compression};
+
+#[cfg(feature = "encryption")]
+mod crypto;
+#[cfg(feature = "encryption")]
+pub use crypto::Keyring;
+
+mod db;
+use db::{now_millis, Db, Stamps};
+pub use db::{BackupProgress, EntryInfo, Stats};
+
+mod deps;
+
+mod export;
+pub use export::{ConflictPolicy, ImportReport};
+
+mod gc;
+pub use gc::{Garbage, GcReport, Roots};
+
+mod memory;
+
+mod migrate;
+pub use migrate::SCHEMA_VERSION;
+
+mod net;
+
+pub mod remote;
+
+pub mod sync;
+
 mod async_impl;
 mod sync_impl;
 
@@ -68,6 +103,85 @@ pub enum StoreError {
     Sqlite { source: sqlite::Error },
     /// A JSON (de)serialization error from the value cache.
     Json { source: serde_json::Error },
+    /// A non-JSON [`Codec`] failed to encode or decode a value.
+    #[snafu(display("{codec:?} codec error: {message}"))]
+    Codec { codec: Codec, message: String },
+    /// Decompressing a stored value failed.
+    #[snafu(display("{tag} decompression error: {message}"))]
+    Compression { tag: String, message: String },
+    /// A row refers to a blob that isn't in the blobs table.
+    #[snafu(display("blob {hash} is missing"))]
+    MissingBlob { hash: String },
+    /// A value failed to encrypt or to decrypt, e.g. because it was
+    /// tampered with.
+    #[snafu(display("encryption error: {message}"))]
+    Encryption { message: String },
+    /// A value is encrypted with a key the store's keyring doesn't have, or
+    /// the store has no keyring.
+    #[snafu(display("value is encrypted with unknown key {key_id:?}"))]
+    UnknownKey { key_id: String },
+    /// A value was written with a [`Codec`] or [`Compression`] this build
+    /// doesn't enable.
+    #[snafu(display("value is encoded with {tag:?}, which is not enabled"))]
+    UnknownCodec { tag: String },
+    /// The wrapped function panicked. Only returned when the call opted in
+    /// via [`Builder::catch_panics`].
+    #[snafu(display("durable function panicked: {message}"))]
+    Panicked { message: String },
+    /// The key has panicked at least `panics` times and is quarantined. See
+    /// [`Builder::quarantine_after`].
+    #[snafu(display("{key:?} is quarantined after {panics} panics"))]
+    Quarantined { key: String, panics: u32 },
+    /// The database was written by a newer `potency` with schema version
+    /// `found`; this build understands up to `supported`.
+    #[snafu(display(
+        "store schema version {found} is newer than this potency supports ({supported}); upgrade potency"
+    ))]
+    SchemaTooNew { found: u32, supported: u32 },
+    /// A database opened with [`StoreOptions::read_only`] is at an older
+    /// schema version and can't be migrated. Open it read-write once first.
+    #[snafu(display(
+        "store schema version {found} is older than {supported} and the store is read-only"
+    ))]
+    SchemaNeedsMigration { found: u32, supported: u32 },
+    /// One of the store's database threads stopped, e.g. because a query
+    /// panicked.
+    #[snafu(display("potency database thread stopped"))]
+    DatabaseThread,
+    /// [`StoreOptions::table_name`] is not a plain SQL identifier.
+    #[snafu(display("{name:?} is not a valid table name"))]
+    InvalidTableName { name: String },
+    /// A cache-only call missed. See [`Builder::run_if_missing_else_error`]
+    /// and [`Mode::CacheOnly`].
+    #[snafu(display("{key:?} is not cached"))]
+    NotCached { key: String },
+    /// [`Store::import`] met a key already in the store under
+    /// [`ConflictPolicy::Fail`]. Nothing was imported.
+    #[snafu(display("{key:?} is already in the store"))]
+    ImportConflict { key: String },
+    /// A line given to [`Store::import`] is not an exported entry.
+    #[snafu(display("line {line} of the import is invalid: {message}"))]
+    InvalidImport { line: u64, message: String },
+    /// An entry read by [`Store::merge_from`], or from the [`remote`]
+    /// cache, is not a valid entry.
+    #[snafu(display("invalid entry: {message}"))]
+    InvalidRecord { message: String },
+    /// A [`sync`] peer broke the protocol or reported an error.
+    #[snafu(display("sync error: {message}"))]
+    Sync { message: String },
+    /// The [`remote`] cache's URL is invalid, or the remote broke the
+    /// protocol.
+    #[snafu(display("remote cache error: {message}"))]
+    Remote { message: String },
+    /// A namespace was listed, or dependencies between keys asked for, in a
+    /// store whose keyring hashes keys (`Keyring::hash_keys`), so rows can't
+    /// be told apart by namespace or named by key.
+    #[snafu(display("keys are hashed, so they can't be listed by namespace"))]
+    HashedKeys,
+    /// An explicit write was requested of a store whose [`Mode`] does not
+    /// write.
+    #[snafu(display("the store does not write in {mode:?} mode"))]
+    WritesDisabled { mode: Mode },
 }
 
 impl From<sqlite::Error> for StoreError {
@@ -98,6 +212,35 @@ pub struct Builder<'a, I, F, C = Sync> {
     key: Vec<String>,
     input: I,
     fn_pair: FnPair<I, F, C>,
+    opts: RunOptions,
+}
+
+/// Per-call options accumulated on a [`Builder`].
+#[derive(Default)]
+struct RunOptions {
+    catch_panics: bool,
+    quarantine_after: Option<u32>,
+    ttl: Option<std::time::Duration>,
+    stale_while_revalidate: Option<std::time::Duration>,
+    /// Replace the row even if a fresh one is already stored. Set by
+    /// [`Builder::refresh`].
+    overwrite: bool,
+    /// Overrides the store's [`StoreOptions::compression`].
+    compression: Option<Compression>,
+    /// Set by [`Builder::pin`].
+    pinned: bool,
+    /// Set by [`Builder::tag`].
+    tags: Vec<String>,
+    /// Type-erased [`Builder::cache_if`] predicate. Always called with the
+    /// builder's output type.
+    #[expect(clippy::type_complexity)]
+    cache_if: Option<Box<dyn Fn(&dyn std::any::Any) -> bool>>,
+}
+
+impl RunOptions {
+    fn should_cache<O: 'static>(&self, output: &O) -> bool {
+        self.cache_if.as_ref().is_none_or(|pred| pred(output))
+    }
 }
 
 impl<'a, C, I: Bundle, F> Builder<'a, I, F, C> {
@@ -110,6 +253,7 @@ impl<'a, C, I: Bundle, F> Builder<'a, I, F, C> {
                 f: self.fn_pair.f,
                 _input: std::marker::PhantomData,
             },
+            opts: self.opts,
         }
     }
 
@@ -117,6 +261,87 @@ impl<'a, C, I: Bundle, F> Builder<'a, I, F, C> {
         self.key.push(input.as_key());
         self.suffix(input)
     }
+
+    /// Whether [`Builder::run`] would be served from the cache right now,
+    /// i.e. a value is stored under this builder's key and has not expired.
+    pub async fn is_cached(&self) -> Result<bool, StoreError> {
+        if !self.store.mode.reads() {
+            return Ok(false);
+        }
+        let key = self.key.join(",");
+        let row = self.store.inner.fetch_value(&key).await?;
+        Ok(row.is_some_and(|row| !row.is_expired(now_millis())))
+    }
+
+    /// Catch panics from the wrapped function.
+    ///
+    /// A panic is turned into [`StoreError::Panicked`] instead of unwinding
+    /// through [`Builder::run`], and is recorded against the key as a failed
+    /// attempt. A later successful run clears the record.
+    pub fn catch_panics(mut self) -> Self {
+        self.opts.catch_panics = true;
+        self
+    }
+
+    /// Expire the stored value `ttl` after it is written.
+    ///
+    /// An expired value counts as a miss: the next call runs the function
+    /// again and replaces the row. Without a TTL values never expire.
+    pub fn ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.opts.ttl = Some(ttl);
+        self
+    }
+
+    /// Serve an expired value for up to `window` past its [`Builder::ttl`]
+    /// while refreshing it in the background.
+    ///
+    /// A call that finds the value stale but inside the window returns it
+    /// at once and hands one refresh to the spawner set with
+    /// [`Store::with_spawner`]. The refresh replaces the row when it
+    /// succeeds; while it is in flight, further stale hits on the same key
+    /// do not schedule another. Past the window, or on a store without a
+    /// spawner, the value is recomputed inline as usual.
+    pub fn stale_while_revalidate(mut self, window: std::time::Duration) -> Self {
+        self.opts.stale_while_revalidate = Some(window);
+        self
+    }
+
+    /// Refuse to run the function once its key has panicked `panics` times.
+    ///
+    /// Implies [`Builder::catch_panics`]. A quarantined key returns
+    /// [`StoreError::Quarantined`] on a miss; cached values are still
+    /// returned. Use [`Store::clear_panics`] to lift the quarantine.
+    pub fn quarantine_after(mut self, panics: u32) -> Self {
+        self.opts.catch_panics = true;
+        self.opts.quarantine_after = Some(panics);
+        self
+    }
+
+    /// Compress this call's value with `compression` instead of the
+    /// store's [`StoreOptions::compression`]. The store's
+    /// [`StoreOptions::compress_above`] threshold still applies.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.opts.compression = Some(compression);
+        self
+    }
+
+    /// Pin the stored value, so that [`Store::gc`] keeps it whatever the
+    /// roots.
+    pub fn pin(mut self) -> Self {
+        self.opts.pinned = true;
+        self
+    }
+
+    /// Tag the stored value with `tag`, so that [`Store::invalidate_tag`]
+    /// deletes it along with everything else tagged the same, in any
+    /// namespace. Call it again for more tags.
+    ///
+    /// Pins and tags are set when the value is stored: a hit keeps those it
+    /// was stored with.
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.opts.tags.push(tag.into());
+        self
+    }
 }
 
 pub struct Async;
@@ -145,6 +370,21 @@ where
     O: serde::Serialize + serde::de::DeserializeOwned + Clone + Send + 'static,
     E: Into<StoreError> + Send + 'static,
 {
+    /// Only cache successful outputs for which `pred` returns `true`.
+    ///
+    /// An output that fails the predicate is still returned to the caller,
+    /// it just isn't stored, so the next call runs the function again. Use
+    /// this for results that are `Ok` but not worth remembering, like an
+    /// empty list returned during an outage.
+    pub fn cache_if(mut self, pred: impl Fn(&O) -> bool + 'static) -> Self {
+        self.opts.cache_if = Some(Box::new(move |output: &dyn std::any::Any| {
+            // UNWRAP: safe because `cache_if` is only reachable with this
+            // builder's `O`, and `fetch_or_else` passes exactly that type.
+            pred(output.downcast_ref::<O>().unwrap())
+        }));
+        self
+    }
+
     /// Run the cached call.
     ///
     /// The cache key is `key.join(",")` — i.e. the namespace segments (added
@@ -152,8 +392,8 @@ where
     /// `.param(...)` arguments. Two entries share a cache slot iff their
     /// joined keys are equal.
     ///
-    /// **Nesting.** The user's function runs *without* the SQLite connection
-    /// lock held, so a durable call may freely invoke other durable calls
+    /// **Nesting.** The user's function runs while no database work is in
+    /// flight, so a durable call may freely invoke other durable calls
     /// (including recursively) without deadlocking.
     pub async fn run(self) -> Result<O, StoreError> {
         let Self {
@@ -161,38 +401,112 @@ where
             key,
             input,
             fn_pair,
+            opts,
+        } = self;
+        let fn_call = fn_pair.construct_fn(input);
+        store.fetch_or_else(key.join(","), opts, fn_call).await
+    }
+
+    /// Run the function and overwrite the cached value, hit or miss.
+    ///
+    /// Uses the same key as [`Builder::run`]. The new value is stored
+    /// subject to the builder's [`Builder::cache_if`] and [`Builder::ttl`].
+    /// In [`Mode::CacheOnly`] nothing runs and this behaves like
+    /// [`Builder::run_if_missing_else_error`].
+    pub async fn refresh(self) -> Result<O, StoreError> {
+        let Self {
+            store,
+            key,
+            input,
+            fn_pair,
+            mut opts,
         } = self;
+        let key = key.join(",");
+        if !store.mode.computes() {
+            return match store.peek(&key).await? {
+                Some(output) => Ok(output),
+                None => NotCachedSnafu { key }.fail(),
+            };
+        }
+        store.inner.depended_on(&key);
         let fn_call = fn_pair.construct_fn(input);
-        store.fetch_or_else(key.join(","), fn_call).await
+        opts.overwrite = true;
+        store.compute(&key, &opts, fn_call).await
+    }
+
+    /// Read the cached value without ever running the function.
+    ///
+    /// Returns `None` on a miss or if the stored value has expired.
+    pub async fn peek(self) -> Result<Option<O>, StoreError> {
+        self.store.peek(&self.key.join(",")).await
+    }
+
+    /// Return the cached value, or [`StoreError::NotCached`] on a miss.
+    ///
+    /// Never runs the function; for callers that must only ever be served
+    /// from the cache.
+    pub async fn run_if_missing_else_error(self) -> Result<O, StoreError> {
+        let key = self.key.join(",");
+        match self.store.peek(&key).await? {
+            Some(output) => Ok(output),
+            None => NotCachedSnafu { key }.fail(),
+        }
     }
 }
 
+/// A background task handed to a [`Store::with_spawner`] spawner.
+///
+/// It wraps the user's function, so it is not `Send`.
+pub type BackgroundTask = Pin<Box<dyn Future<Output = ()>>>;
+
+type Spawner = Arc<dyn Fn(BackgroundTask) + Send + std::marker::Sync>;
+
 #[derive(Clone)]
 pub struct Store {
     key: Vec<String>,
-    inner: Arc<async_lock::Mutex<sqlite::Connection>>,
+    mode: Mode,
+    /// How new values are written; see [`StoreOptions::codec`] and
+    /// [`StoreOptions::compression`].
+    encoding: Encoding,
+    inner: Arc<Db>,
+    spawner: Option<Spawner>,
+    /// Keys with a stale-while-revalidate refresh in flight.
+    refreshing: Arc<std::sync::Mutex<std::collections::HashSet<String>>>,
 }
 
 impl Store {
     /// Open a SQLite-backed store at `path`. Use `":memory:"` for an
     /// in-memory database (tests); pass a file path for persistence.
     pub async fn open(path: impl AsRef<std::path::Path>) -> Result<Self, StoreError> {
-        let inner = Arc::new(async_lock::Mutex::new({
-            sqlite::Connection::open_with_flags(
-                path,
-                sqlite::OpenFlags::default().with_create().with_read_write(),
-            )?
-        }));
-        // Run migrations.
-        {
-            let guard = inner.lock().await;
-            let query = r#"CREATE TABLE IF NOT EXISTS potency(
-                key TEXT PRIMARY KEY NOT NULL,
-                value TEXT NOT NULL
-            )"#;
-            guard.execute(query)?;
-        }
-        Ok(Self { key: vec![], inner })
+        Self::open_with(path, StoreOptions::default()).await
+    }
+
+    /// Open a SQLite-backed store at `path` with the given [`StoreOptions`].
+    ///
+    /// A store opened with [`StoreOptions::read_only`] runs in
+    /// [`Mode::ReadOnly`] unless another non-writing mode was chosen.
+    pub async fn open_with(
+        path: impl AsRef<std::path::Path>,
+        options: StoreOptions,
+    ) -> Result<Self, StoreError> {
+        let inner = Arc::new(Db::open(path, &options).await?);
+        let mode = match options.mode {
+            Mode::ReadWrite if options.read_only => Mode::ReadOnly,
+            mode => mode,
+        };
+        Ok(Self {
+            key: vec![],
+            mode,
+            encoding: Encoding {
+                codec: options.codec,
+                compression: options.compression,
+                compress_above: options.compress_above,
+                blobs_above: options.blobs_above,
+            },
+            inner,
+            spawner: None,
+            refreshing: Default::default(),
+        })
     }
 
     /// Open an in-memory store. Convenience for tests.
@@ -206,182 +520,1041 @@ impl Store {
     /// the `Ok` value is serialized and stored; on `Err` the value is
     /// returned to the caller and **not** stored.
     ///
-    /// **Locking.** The SQLite connection is locked only for the brief
-    /// fetch/store round-trips. The user's function `f` runs *without* the
-    /// lock held, so a durable call may invoke other durable calls (or
+    /// **Threading.** The fetch and the store are short jobs on the store's
+    /// database threads, so blocking SQLite calls never run on the caller's
+    /// executor. The user's function `f` runs on the caller's task with no
+    /// job in flight, so a durable call may invoke other durable calls (or
     /// recurse) without deadlocking.
     ///
     /// **Concurrent same-key misses.** Two tasks that miss the same key
-    /// concurrently will both compute; the second writer, on re-acquiring
-    /// the lock, observes the first writer's stored value and returns it
+    /// concurrently will both compute; the second writer, in its store job,
+    /// observes the first writer's stored value and returns it
     /// instead of overwriting. The cost is one redundant compute per pair;
     /// the observable result is the same for any deterministic function.
+    ///
+    /// **Expiry.** A row past its [`Builder::ttl`] counts as a miss, unless
+    /// it is still inside the [`Builder::stale_while_revalidate`] window: then
+    /// the stale value is returned and `f` is handed to the store's spawner
+    /// to refresh the row in the background.
+    ///
+    /// **Conditional caching.** An `Ok` output rejected by the
+    /// [`Builder::cache_if`] predicate is returned without being stored.
+    ///
+    /// **Modes.** The store's [`Mode`] decides whether the lookup, the
+    /// computation and the store happen at all; see its variants.
+    ///
+    /// **Panics.** With `opts.catch_panics` set, a panic from `f` is caught
+    /// and recorded in the store's failures table; see
+    /// [`Builder::catch_panics`].
     fn fetch_or_else<'a, O, E, Fut>(
         &'a self,
         key: impl AsRef<str> + 'a,
-        f: impl FnOnce() -> Fut + 'a,
+        opts: RunOptions,
+        f: impl FnOnce() -> Fut + 'static,
     ) -> Pin<Box<dyn Future<Output = Result<O, StoreError>> + 'a>>
     where
-        Fut: Future<Output = Result<O, E>> + 'a,
+        Fut: Future<Output = Result<O, E>> + 'static,
         O: serde::Serialize + serde::de::DeserializeOwned + Clone + Send + 'static,
         E: Into<StoreError>,
     {
         let full_key = key.as_ref().to_owned();
+        self.inner.depended_on(&full_key);
         Box::pin(async move {
-            // Step 1: brief lock to fetch.
-            let maybe_value: Option<serde_json::Value> = {
-                let mut lock = self.inner.lock().await;
-                fetch_value(&mut lock, &full_key).await?
-            };
-            if let Some(json_value) = maybe_value {
-                log::trace!("{full_key:?} is cached, returning cache hit");
-                let output: O = serde_json::from_value(json_value)?;
+            // Step 1: fetch, from memory first if the store keeps values there.
+            let memory = self.inner.memory().filter(|_| self.mode.reads());
+            if let Some(output) = memory.and_then(|memory| memory.get::<O>(&full_key, now_millis()))
+            {
+                log::trace!("{full_key:?} is in memory, returning cache hit");
+                if self.mode.writes() {
+                    self.inner.record_hit(&full_key);
+                }
                 return Ok(output);
             }
-            log::trace!("{full_key:?} is not cached, computing the value");
-
-            // Step 2: user work — NO LOCK held. This is what makes
-            // durable-in-durable and recursive durable calls safe.
-            let output = f().await.map_err(Into::into)?;
-
-            // Step 3: brief lock to store, with re-check for racing writers.
-            let mut lock = self.inner.lock().await;
-            if let Some(existing) = fetch_value(&mut lock, &full_key).await? {
-                log::trace!("{full_key:?} racing writer detected, using their value");
-                let output: O = serde_json::from_value(existing)?;
+            let epoch = memory.map(|memory| memory.epoch());
+            let maybe_row = if self.mode.reads() {
+                self.inner.fetch_value(&full_key).await?
+            } else {
+                None
+            };
+            if let Some(row) = maybe_row {
+                let now = now_millis();
+                if !row.is_expired(now) {
+                    log::trace!("{full_key:?} is cached, returning cache hit");
+                    let output: O = row.decode()?;
+                    if let (Some(memory), Some(epoch)) = (memory, epoch) {
+                        memory.insert(&full_key, epoch, output.clone(), row.expires_at);
+                    }
+                    if self.mode.writes() {
+                        self.inner.record_hit(&full_key);
+                    }
+                    return Ok(output);
+                }
+                if row.is_servable_stale(now, opts.stale_while_revalidate) {
+                    if !self.mode.writes() {
+                        log::trace!("{full_key:?} is stale, serving it without a refresh");
+                        let output: O = row.decode()?;
+                        return Ok(output);
+                    }
+                    if let Some(spawner) = self.spawner.as_ref() {
+                        let output: O = row.decode()?;
+                        self.inner.record_hit(&full_key);
+                        self.spawn_refresh(spawner, full_key, opts, f);
+                        return Ok(output);
+                    }
+                    log::warn!(
+                        "{full_key:?} is stale but the store has no spawner, refreshing inline"
+                    );
+                }
+                log::trace!("{full_key:?} is expired, recomputing the value");
+            } else {
+                log::trace!("{full_key:?} is not cached, computing the value");
+            }
+            if let Some(output) = self.fetch_remote(&full_key).await? {
                 return Ok(output);
             }
-            let json_value = serde_json::to_value(output.clone())?;
-            store_value(&mut lock, &full_key, &json_value).await?;
-            Ok(output)
+            if !self.mode.computes() {
+                return NotCachedSnafu { key: full_key }.fail();
+            }
+            self.compute(&full_key, &opts, f).await
         })
     }
 
-    /// Attach a namespace segment to subsequent calls.
-    pub fn namespace(&self, namespace: impl AsRef<str>) -> Self {
-        let namespace = namespace.as_ref().to_string();
-        let mut store = self.clone();
-        log::trace!("store '{:?}' adding '{namespace}'", store.key);
-        store.key.push(namespace);
-        store
+    /// Run `f` and store its output under `key`.
+    ///
+    /// This is steps 2 and 3 of [`Store::fetch_or_else`]: the quarantine
+    /// check, the (optionally panic-catching) call, and the store with its
+    /// re-check for racing writers. A racing writer's row only wins if it
+    /// is still fresh.
+    async fn compute<O, E, Fut>(
+        &self,
+        full_key: &str,
+        opts: &RunOptions,
+        f: impl FnOnce() -> Fut,
+    ) -> Result<O, StoreError>
+    where
+        Fut: Future<Output = Result<O, E>>,
+        O: serde::Serialize + serde::de::DeserializeOwned + Clone + Send + 'static,
+        E: Into<StoreError>,
+    {
+        if let (Some(limit), true) = (opts.quarantine_after, self.mode.reads()) {
+            let panics = self.inner.fetch_panics(full_key).await?;
+            if panics >= limit {
+                log::trace!("{full_key:?} is quarantined after {panics} panics");
+                return QuarantinedSnafu {
+                    key: full_key,
+                    panics,
+                }
+                .fail();
+            }
+        }
+
+        // Step 2: user work — no database job in flight. This is what makes
+        // durable-in-durable and recursive durable calls safe. The durable
+        // calls it makes are recorded as its dependencies.
+        let computation = self.inner.computation();
+        let f = deps::track(computation.clone(), f);
+        let started = std::time::Instant::now();
+        let output = if opts.catch_panics {
+            match catch_panic(f).await {
+                Ok(result) => result.map_err(Into::into)?,
+                Err(message) => {
+                    log::trace!("{full_key:?} panicked: {message}");
+                    if self.mode.writes() {
+                        self.inner.record_panic(full_key, &message).await?;
+                    }
+                    return PanickedSnafu { message }.fail();
+                }
+            }
+        } else {
+            f().await.map_err(Into::into)?
+        };
+
+        let compute_duration = started.elapsed();
+
+        if !self.mode.writes() {
+            log::trace!("{full_key:?} computed, not storing in {:?} mode", self.mode);
+            return Ok(output);
+        }
+
+        // Step 3: store, with re-check for racing writers. This is a single
+        // job on the writer thread, so nothing can slip in between.
+        let cache = opts.should_cache(&output);
+        if !cache {
+            log::trace!("{full_key:?} rejected by cache_if, not storing");
+        }
+        let encoded = match cache {
+            true => Some(self.encoding.codec.encode(&output)?),
+            false => None,
+        };
+        let encoding = Encoding {
+            compression: opts.compression.unwrap_or(self.encoding.compression),
+            ..self.encoding
+        };
+        // Conservative: the row's own expiry is stamped a little later.
+        let expires_at = opts
+            .ttl
+            .map(|ttl| now_millis().saturating_add(ttl.as_millis() as i64));
+        let publish = self.inner.remote().is_some();
+        let (key, catch_panics, overwrite, ttl) = (
+            full_key.to_owned(),
+            opts.catch_panics,
+            opts.overwrite,
+            opts.ttl,
+        );
+        let (pinned, tags) = (opts.pinned, opts.tags.clone());
+        let deps = computation.take();
+        let (existing, record) = self
+            .inner
+            .write(move |conn| {
+                if catch_panics {
+                    conn.clear_panics(&key)?;
+                }
+                let Some(encoded) = encoded else {
+                    return Ok((None, None));
+                };
+                if let Some(existing) = conn.fetch_value(&key)? {
+                    // An expired row is the one we are replacing, not a racer's.
+                    if !overwrite && !existing.is_expired(now_millis()) {
+                        return Ok((Some(existing), None));
+                    }
+                }
+                let stamps = Stamps {
+                    pinned,
+                    tags,
+                    ..Stamps::new(ttl, Some(compute_duration))
+                };
+                conn.store_value(&key, encoding, &encoded, &stamps)?;
+                conn.depend(&key, &deps)?;
+                let record = match publish {
+                    true => conn.record(&key)?,
+                    false => None,
+                };
+                Ok((None, record))
+            })
+            .await?;
+        if let (Some(remote), Some(record)) = (self.inner.remote(), record) {
+            remote.put(record);
+        }
+        if let Some(existing) = existing {
+            log::trace!("{full_key:?} racing writer detected, using their value");
+            let output: O = existing.decode()?;
+            if let Some(memory) = self.inner.memory() {
+                memory.replace(full_key, output.clone(), existing.expires_at);
+            }
+            return Ok(output);
+        }
+        if let (Some(memory), true) = (self.inner.memory(), cache) {
+            memory.replace(full_key, output.clone(), expires_at);
+        }
+        Ok(output)
     }
 
-    /// Begin a sync entry.
-    pub fn entry<F>(&self, f: F) -> Builder<'_, (), F> {
-        let _input: PhantomData<(Sync, ())> = PhantomData;
-        let fn_pair: FnPair<(), F, Sync> = FnPair { f, _input };
-        Builder {
-            store: self,
-            // Inherit the Store's accumulated namespace segments so the
-            // resulting cache key reflects both the namespace and the
-            // params added via `.param(...)`.
-            key: self.key.clone(),
-            input: (),
-            fn_pair,
+    /// Look `full_key` up in the remote tier, storing a hit locally.
+    ///
+    /// A remote that fails counts as a miss.
+    async fn fetch_remote<O>(&self, full_key: &str) -> Result<Option<O>, StoreError>
+    where
+        O: serde::de::DeserializeOwned + Clone + Send + 'static,
+    {
+        let Some(remote) = self.inner.remote().filter(|_| self.mode.reads()) else {
+            return Ok(None);
+        };
+        let entry = match remote.get(full_key).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                log::warn!("{full_key:?} remote lookup failed: {e}");
+                return Ok(None);
+            }
+        };
+        let expires_at = entry.stamps.expires_at;
+        let Some((codec, bytes)) = entry.value.as_ref() else {
+            return Ok(None);
+        };
+        if expires_at.is_some_and(|at| now_millis() >= at) {
+            log::trace!("{full_key:?} has expired in the remote cache too");
+            return Ok(None);
+        }
+        log::trace!("{full_key:?} is in the remote cache, returning cache hit");
+        let output: O = codec.decode(bytes)?;
+        if self.mode.writes() {
+            let encoding = self.encoding;
+            self.inner
+                .write(move |conn| conn.import(vec![entry], encoding, ConflictPolicy::Overwrite))
+                .await?;
+            if let Some(memory) = self.inner.memory() {
+                memory.replace(full_key, output.clone(), expires_at);
+            }
         }
+        Ok(Some(output))
     }
 
-    /// Begin an async entry.
-    pub fn entry_async<F>(&self, f: F) -> Builder<'_, (), F, Async> {
-        Builder {
-            store: self,
-            key: self.key.clone(),
-            input: (),
-            fn_pair: FnPair {
-                f,
-                _input: std::marker::PhantomData,
-            },
+    /// Decode the unexpired value stored under `full_key`, if any.
+    async fn peek<O: serde::de::DeserializeOwned + Clone + Send + 'static>(
+        &self,
+        full_key: &str,
+    ) -> Result<Option<O>, StoreError> {
+        if !self.mode.reads() {
+            return Ok(None);
+        }
+        let memory = self.inner.memory();
+        if let Some(output) = memory.and_then(|memory| memory.get::<O>(full_key, now_millis())) {
+            return Ok(Some(output));
+        }
+        let epoch = memory.map(|memory| memory.epoch());
+        let row = self.inner.fetch_value(full_key).await?;
+        match row {
+            Some(row) if !row.is_expired(now_millis()) => {
+                let output: O = row.decode()?;
+                if let (Some(memory), Some(epoch)) = (memory, epoch) {
+                    memory.insert(full_key, epoch, output.clone(), row.expires_at);
+                }
+                Ok(Some(output))
+            }
+            _ => Ok(None),
         }
     }
 
-    /// Begin a durable side-effect entry. See [`effect::fs_effect`] for the
-    /// common filesystem case.
-    pub fn effect<E>(&self, effect: E) -> EffectBuilder<'_, E> {
-        EffectBuilder {
-            store: self,
-            key: self.key.clone(),
-            effect,
+    /// Hand a background refresh of `full_key` to `spawner`.
+    ///
+    /// At most one refresh per key is in flight at a time; a stale hit that
+    /// finds a refresh already scheduled just returns the stale value.
+    fn spawn_refresh<O, E, Fut>(
+        &self,
+        spawner: &Spawner,
+        full_key: String,
+        opts: RunOptions,
+        f: impl FnOnce() -> Fut + 'static,
+    ) where
+        Fut: Future<Output = Result<O, E>> + 'static,
+        O: serde::Serialize + serde::de::DeserializeOwned + Clone + Send + 'static,
+        E: Into<StoreError>,
+    {
+        // UNWRAP: safe because the set is only touched in short, non-panicking
+        // critical sections.
+        if !self.refreshing.lock().unwrap().insert(full_key.clone()) {
+            log::trace!("{full_key:?} is stale, refresh already in flight");
+            return;
         }
+        log::trace!("{full_key:?} is stale, scheduling a background refresh");
+        // Created before the task so that the key is released however the
+        // task ends: finished, panicked, or dropped without finishing.
+        let in_flight = InFlight {
+            refreshing: self.refreshing.clone(),
+            key: full_key.clone(),
+        };
+        let store = self.clone();
+        spawner(Box::pin(async move {
+            let _in_flight = in_flight;
+            let result = store.compute::<O, E, Fut>(&full_key, &opts, f).await;
+            if let Err(e) = result {
+                log::warn!("{full_key:?} background refresh failed: {e}");
+            }
+        }));
     }
-}
 
-async fn fetch_value(
-    lock: &mut async_lock::MutexGuard<'_, sqlite::Connection>,
-    key: &str,
-) -> Result<Option<serde_json::Value>, StoreError> {
-    log::trace!("fetching {key}");
-    let query = "SELECT value FROM potency WHERE key = :key";
-    let mut statement = lock.prepare(query)?;
-    statement.bind((":key", key))?;
-    match statement.next()? {
-        sqlite::State::Row => {
-            let string_value = statement.read::<String, _>("value")?;
-            let value: serde_json::Value = serde_json::from_str(&string_value)?;
-            Ok(Some(value))
-        }
-        sqlite::State::Done => Ok(None),
+    /// Timestamps, hit count and compute duration of the entry stored under
+    /// `key`, or `None` if nothing is.
+    ///
+    /// `key` is the full cache key, i.e. the namespace segments and params
+    /// joined with `","`.
+    pub async fn entry_info(&self, key: impl AsRef<str>) -> Result<Option<EntryInfo>, StoreError> {
+        self.inner.entry_info(key.as_ref()).await
     }
-}
 
-async fn store_value(
-    lock: &mut async_lock::MutexGuard<'_, sqlite::Connection>,
-    key: &str,
-    value: &serde_json::Value,
-) -> Result<(), StoreError> {
-    // UNWRAP: safe because `Value` always serializes.
-    let serialized = serde_json::to_string(value).unwrap();
-    log::trace!("storing key {key}: {serialized}");
-    let query = "INSERT OR REPLACE INTO potency (key, value) VALUES (:key, :value)";
-    let mut statement = lock.prepare(query)?;
-    statement.bind(&[(":key", key), (":value", serialized.as_str())][..])?;
-    let _ = statement.next()?;
-    Ok(())
-}
+    /// Every key in this view's namespace, in order, with its
+    /// [`EntryInfo`]. Expired entries are included.
+    ///
+    /// Keys are listed a page at a time, each page a short job on the
+    /// store's database threads, so the stream can be consumed slowly
+    /// without holding up other calls. Writes made while the stream is
+    /// consumed may or may not show up in it.
+    ///
+    /// A store whose keyring hashes keys (`Keyring::hash_keys`) lists the
+    /// hashes, and only the root view can list them; a namespace view
+    /// yields [`StoreError::HashedKeys`].
+    ///
+    /// ```rust
+    /// # async fn doc() -> Result<(), potency::StoreError> {
+    /// use futures_lite::StreamExt;
+    /// use potency::Store;
+    ///
+    /// let store = Store::in_memory().await?;
+    /// let users = store.namespace("users");
+    /// users.entry(|id: u32| Ok::<_, potency::Error>(id * 2)).param(1u32).run().await?;
+    /// let mut entries = users.iter();
+    /// while let Some((key, info)) = entries.try_next().await? {
+    ///     println!("{key}: {} hits", info.hit_count);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn iter(
+        &self,
+    ) -> impl futures_lite::Stream<Item = Result<(String, EntryInfo), StoreError>> + Unpin + 'static
+    {
+        self.inner
+            .scan(self.key.join(","), |conn, prefix, after, limit| {
+                conn.list_entries(prefix, after, limit)
+            })
+    }
 
-async fn delete_value(
-    lock: &mut async_lock::MutexGuard<'_, sqlite::Connection>,
-    key: &str,
-) -> Result<(), StoreError> {
-    let mut statement = lock.prepare("DELETE FROM potency WHERE key = :key")?;
-    statement.bind((":key", key))?;
-    let _ = statement.next()?;
-    Ok(())
-}
+    /// Every key in this view's namespace, in order. See [`Store::iter`].
+    pub fn keys(
+        &self,
+    ) -> impl futures_lite::Stream<Item = Result<String, StoreError>> + Unpin + 'static {
+        use futures_lite::StreamExt;
+        self.iter().map(|entry| entry.map(|(key, _)| key))
+    }
 
-// ============================================================================
-// Global store for `potency-macros`
-// ============================================================================
+    /// Every unexpired value in this view's namespace, with its key,
+    /// decoded as `O`. See [`Store::iter`].
+    ///
+    /// Values are decoded one at a time, so a namespace holding values of
+    /// several types yields an error for each value that isn't an `O`.
+    pub fn iter_values<O: serde::de::DeserializeOwned>(
+        &self,
+    ) -> impl futures_lite::Stream<Item = Result<(String, O), StoreError>> + Unpin + 'static {
+        use futures_lite::StreamExt;
+        self.inner
+            .scan(self.key.join(","), |conn, prefix, after, limit| {
+                conn.list_values(prefix, after, limit)
+            })
+            .filter_map(|entry| match entry {
+                Ok((_, row)) if row.is_expired(now_millis()) => None,
+                Ok((key, row)) => Some(row.decode().map(|value| (key, value))),
+                Err(e) => Some(Err(e)),
+            })
+    }
 
-static GLOBAL_STORE: std::sync::OnceLock<Store> = std::sync::OnceLock::new();
+    /// Write every entry in this view's namespace to `writer` as JSON Lines,
+    /// with its bookkeeping, returning how many were written. Read them
+    /// back with [`Store::import`].
+    ///
+    /// Values are written decompressed and **decrypted**. Entries are read
+    /// a page at a time, as with [`Store::iter`].
+    ///
+    /// ```rust
+    /// # async fn doc() -> Result<(), potency::StoreError> {
+    /// use potency::{ConflictPolicy, Store};
+    ///
+    /// let store = Store::in_memory().await?;
+    /// store.entry(|x: u32| Ok::<_, potency::Error>(x + 1)).param(1u32).run().await?;
+    /// let mut fixture = Vec::new();
+    /// store.export(&mut fixture).await?;
+    ///
+    /// let copy = Store::in_memory().await?;
+    /// let report = copy.import(fixture.as_slice(), ConflictPolicy::Fail).await?;
+    /// assert_eq!(report.imported, 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn export(&self, mut writer: impl std::io::Write) -> Result<u64, StoreError> {
+        use futures_lite::StreamExt;
+        let mut records = self
+            .inner
+            .scan(self.key.join(","), |conn, prefix, after, limit| {
+                conn.list_records(prefix, after, limit)
+            });
+        let mut exported = 0;
+        while let Some((_, record)) = records.try_next().await? {
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+            exported += 1;
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
 
-/// Install the process-global [`Store`] used by `potency-macros`.
-///
-/// One-shot: subsequent calls return [`AlreadyInstalled`].
-pub fn install_global_store(store: Store) -> Result<&'static Store, AlreadyInstalled> {
-    GLOBAL_STORE.set(store).map_err(|_| AlreadyInstalled)?;
-    Ok(GLOBAL_STORE.get().unwrap())
-}
+    /// Read entries written by [`Store::export`] or
+    /// [`Store::changes_since`] from `reader` and store them, resolving keys
+    /// already in the store by `policy`.
+    ///
+    /// Entries keep their keys, whatever this view's namespace, and their
+    /// bookkeeping: creation and expiry times, hits and compute duration.
+    /// Values are compressed, moved to blobs and encrypted by this store's
+    /// [`StoreOptions`]. Lines are read and written in batches, but the
+    /// import is one transaction: if any line is invalid or conflicts under
+    /// [`ConflictPolicy::Fail`], nothing is stored.
+    ///
+    /// Deletions from [`Store::changes_since`] delete the store's entry,
+    /// subject to `policy` like any other conflict: under
+    /// [`ConflictPolicy::LastWriterWins`] only an entry created before the
+    /// deletion is deleted.
+    ///
+    /// Keys exported from a store that hashes keys (`Keyring::hash_keys`)
+    /// are imported as they are, so they are only found by a store hashing
+    /// with the same secret.
+    pub async fn import(
+        &self,
+        reader: impl futures_lite::AsyncBufRead + Unpin,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport, StoreError> {
+        use futures_lite::{AsyncBufReadExt, StreamExt};
+        self.ensure_writes()?;
+        let entries = reader
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|(i, line)| export::Record::parse(i as u64 + 1, &line?));
+        self.import_entries(entries, policy).await
+    }
 
-/// Read the global store, if installed.
-pub fn global_store() -> Option<&'static Store> {
-    GLOBAL_STORE.get()
-}
+    /// Write imported `entries` in one transaction, handing them to the
+    /// writer [`db::IMPORT_BATCH`] at a time, and drop whatever the in-memory
+    /// tier holds since any of it may have been replaced.
+    async fn import_entries(
+        &self,
+        mut entries: impl futures_lite::Stream<Item = Result<export::Imported, StoreError>> + Unpin,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport, StoreError> {
+        use futures_lite::StreamExt;
+        let encoding = self.encoding;
+        let (batches, received) = async_channel::bounded(1);
+        let write = self
+            .inner
+            .write(move |conn| conn.import_batches(&received, encoding, policy));
+        let feed = async move {
+            let mut batch = Vec::with_capacity(db::IMPORT_BATCH);
+            while let Some(entry) = entries.next().await {
+                batch.push(entry?);
+                if batch.len() == db::IMPORT_BATCH {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(db::IMPORT_BATCH));
+                    if batches.send(full).await.is_err() {
+                        // The import failed; its error says why.
+                        return Ok(());
+                    }
+                }
+            }
+            if !batch.is_empty() {
+                let _ = batches.send(batch).await;
+            }
+            // An empty batch ends the import. If the import already failed,
+            // its error says why.
+            let _ = batches.send(Vec::new()).await;
+            Ok::<_, StoreError>(())
+        };
+        let (report, fed) = futures_lite::future::zip(write, feed).await;
+        self.clear_memory();
+        fed?;
+        report
+    }
 
-/// Returned by [`install_global_store`] when a store was already installed.
-#[derive(Debug)]
-pub struct AlreadyInstalled;
+    /// Drop whatever the in-memory tier holds.
+    fn clear_memory(&self) {
+        if let Some(memory) = self.inner.memory() {
+            memory.clear();
+        }
+    }
 
-impl std::fmt::Display for AlreadyInstalled {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "potency: global store already installed")
+    /// Copy every entry in `other`'s namespace into this store, resolving
+    /// keys already here by `policy`, as [`Store::import`] does. `other` may
+    /// be any store, e.g. one opened on a file copied from another machine.
+    ///
+    /// The entries are read a page at a time, each page written as it is
+    /// read, all in one transaction.
+    pub async fn merge_from(
+        &self,
+        other: &Store,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport, StoreError> {
+        use futures_lite::StreamExt;
+        self.ensure_writes()?;
+        if Arc::ptr_eq(&self.inner, &other.inner) {
+            // Views of one database: reading `other` page by page while the
+            // import holds the writer would wait on the import itself.
+            let (prefix, encoding) = (other.key.join(","), self.encoding);
+            let report = self
+                .inner
+                .write(move |conn| conn.import_own(&prefix, encoding, policy))
+                .await;
+            self.clear_memory();
+            return report;
+        }
+        let entries = other
+            .inner
+            .scan(other.key.join(","), |conn, prefix, after, limit| {
+                conn.list_records(prefix, after, limit)
+            })
+            .map(|record| record?.1.into_imported(None));
+        self.import_entries(entries, policy).await
     }
-}
 
-impl std::error::Error for AlreadyInstalled {}
+    /// Write every change to the store after change number `seq` to
+    /// `writer`, returning the number of the last one. Pass 0 for every
+    /// entry in the store.
+    ///
+    /// Every write and deletion, in any namespace, is numbered in order;
+    /// cache hits don't count. Only a key's latest change is kept, so a
+    /// key written many times since `seq` is written out once, as it is
+    /// now. Changes are written as JSON Lines, in the format of
+    /// [`Store::export`] plus a line for each deletion, for another
+    /// store's [`Store::import`]. Keep the returned number to ship only
+    /// newer changes next time:
+    ///
+    /// ```rust,no_run
+    /// # async fn doc(store: potency::Store, last: u64) -> Result<u64, potency::StoreError> {
+    /// let delta = std::fs::File::create("delta.jsonl")?;
+    /// let last = store.changes_since(last, std::io::BufWriter::new(delta)).await?;
+    ///
+    /// // On another machine:
+    /// let delta = smol::fs::File::open("delta.jsonl").await?;
+    /// let delta = futures_lite::io::BufReader::new(delta);
+    /// store.import(delta, potency::ConflictPolicy::LastWriterWins).await?;
+    /// # Ok(last)
+    /// # }
+    /// ```
+    pub async fn changes_since(
+        &self,
+        seq: u64,
+        mut writer: impl std::io::Write,
+    ) -> Result<u64, StoreError> {
+        let mut last = seq;
+        loop {
+            let (next, more) = self.changes_page(last, &mut writer).await?;
+            last = next;
+            if !more {
+                break;
+            }
+        }
+        writer.flush()?;
+        Ok(last)
+    }
+
+    /// Write a page of the changes after change number `seq` to `writer`,
+    /// as [`Store::changes_since`] does, returning the number of the last
+    /// one and whether there are more.
+    pub(crate) async fn changes_page(
+        &self,
+        seq: u64,
+        mut writer: impl std::io::Write,
+    ) -> Result<(u64, bool), StoreError> {
+        let changes = self
+            .inner
+            .read_latest(move |conn| conn.list_changes(seq, db::SCAN_PAGE))
+            .await?;
+        let mut last = seq;
+        for (seq, record) in &changes {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+            last = *seq;
+        }
+        Ok((last, changes.len() == db::SCAN_PAGE))
+    }
+
+    /// Write a consistent copy of the whole database, every table and
+    /// namespace, to `path`, calling `progress` as pages are copied.
+    /// Reopen the copy with [`Store::open`] to restore it.
+    ///
+    /// The copy uses SQLite's online backup API and reads one snapshot
+    /// of the database, so a store can be backed up while it is in use. It
+    /// runs on one of the store's [readers](StoreOptions::readers), leaving
+    /// the others and the writer free. A store without readers, or with
+    /// [`StoreOptions::write_behind`], runs it on the writer instead, so
+    /// its calls wait until the copy is done; batched writes are committed
+    /// first. Other processes keep reading, and in [`JournalMode::Wal`]
+    /// writing, throughout.
+    ///
+    /// The copy is written next to `path` and only renamed to it once
+    /// complete, so an interrupted backup never leaves a torn file at
+    /// `path`.
+    ///
+    /// ```rust,no_run
+    /// # async fn doc() -> Result<(), potency::StoreError> {
+    /// use potency::Store;
+    ///
+    /// let store = Store::open("state.db").await?;
+    /// store
+    ///     .backup_to("nightly.db", |progress| {
+    ///         log::info!("backup {:.0}% done", progress.fraction() * 100.0)
+    ///     })
+    ///     .await?;
+    /// let restored = Store::open("nightly.db").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn backup_to(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        mut progress: impl FnMut(BackupProgress) + Send + 'static,
+    ) -> Result<BackupProgress, StoreError> {
+        let path = path.as_ref().to_owned();
+        self.inner
+            .read_latest(move |conn| conn.backup_to(&path, &mut progress))
+            .await
+    }
+
+    /// Entry counts and sizes for the whole store, across namespaces.
+    pub async fn stats(&self) -> Result<Stats, StoreError> {
+        self.inner.stats().await
+    }
+
+    /// Delete every entry tagged `tag` with [`Builder::tag`] or
+    /// [`EffectBuilder::tag`], in any namespace, pinned or not. Returns how
+    /// many were deleted.
+    ///
+    /// The next call for each recomputes it, or produces its effect again.
+    pub async fn invalidate_tag(&self, tag: impl AsRef<str>) -> Result<u64, StoreError> {
+        self.ensure_writes()?;
+        let tag = tag.as_ref().to_owned();
+        let deleted = self
+            .inner
+            .write(move |conn| conn.invalidate_tag(&tag))
+            .await;
+        if let Some(memory) = self.inner.memory() {
+            memory.clear();
+        }
+        deleted
+    }
+
+    /// The keys of the entries the entry under `key` read while it was last
+    /// computed, sorted by key.
+    ///
+    /// An entry reads another when its function, or an effect's
+    /// [`Effect::produce`], makes a durable call through the same store
+    /// while it runs: from the function itself or anything it awaits, but
+    /// not from tasks it spawns. Calls served from the cache count too. The
+    /// edges are kept until the entry is computed again or deleted, even if
+    /// the entries it read are deleted first.
+    ///
+    /// `key` is the full cache key, i.e. the namespace segments and params
+    /// joined with `","`. Fails with [`StoreError::HashedKeys`] if the
+    /// keyring hashes keys (`Keyring::hash_keys`).
+    ///
+    /// ```rust
+    /// # async fn doc() -> Result<(), potency::StoreError> {
+    /// use potency::Store;
+    ///
+    /// async fn rate(currency: String) -> Result<f64, potency::StoreError> {
+    ///     Ok(if currency == "EUR" { 1.1 } else { 1.0 })
+    /// }
+    ///
+    /// let store = Store::in_memory().await?;
+    /// let inner = store.clone();
+    /// let price = move |cents: u32| {
+    ///     let store = inner.clone();
+    ///     async move {
+    ///         let rate = store
+    ///             .namespace("rate")
+    ///             .entry_async(rate)
+    ///             .param("EUR".to_owned())
+    ///             .run()
+    ///             .await?;
+    ///         Ok::<_, potency::StoreError>(cents as f64 * rate)
+    ///     }
+    /// };
+    /// store.namespace("price").entry_async(price).param(100u32).run().await?;
+    ///
+    /// assert_eq!(store.dependencies("price,100").await?, ["rate,EUR"]);
+    /// assert_eq!(store.dependents("rate,EUR").await?, ["price,100"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn dependencies(&self, key: impl AsRef<str>) -> Result<Vec<String>, StoreError> {
+        let key = key.as_ref().to_owned();
+        self.inner
+            .read_latest(move |conn| conn.dependencies(&key))
+            .await
+    }
+
+    /// The keys of the entries that read the entry under `key` while they
+    /// were last computed, sorted by key. The reverse of
+    /// [`Store::dependencies`].
+    pub async fn dependents(&self, key: impl AsRef<str>) -> Result<Vec<String>, StoreError> {
+        let key = key.as_ref().to_owned();
+        self.inner
+            .read_latest(move |conn| conn.dependents(&key))
+            .await
+    }
+
+    /// What [`Store::gc`] would delete, without deleting anything.
+    pub async fn gc_dry_run(&self, roots: &Roots) -> Result<GcReport, StoreError> {
+        let (prefix, roots) = (self.key.join(","), roots.clone());
+        self.inner
+            .read_latest(move |conn| conn.collect_garbage(&prefix, &roots, true))
+            .await
+    }
+
+    /// Delete every entry in this view's namespace that isn't under one of
+    /// `roots` or [pinned](Builder::pin), with the panics recorded against
+    /// it and the blobs no entry uses any more. Returns what was deleted.
+    ///
+    /// Meant for cleaning up after refactors, when functions are renamed or
+    /// removed and their namespaces are left behind. [`Roots::durable`]
+    /// keeps every `#[durable]` function's namespace; anything used through
+    /// [`Store::entry`] or [`Store::effect`] directly has to be added as a
+    /// prefix, or it is deleted too. Check with [`Store::gc_dry_run`]
+    /// first.
+    ///
+    /// Fails with [`StoreError::HashedKeys`] if the keyring hashes keys
+    /// (`Keyring::hash_keys`), since keys can't be told apart then.
+    pub async fn gc(&self, roots: &Roots) -> Result<GcReport, StoreError> {
+        self.ensure_writes()?;
+        let (prefix, roots) = (self.key.join(","), roots.clone());
+        let report = self
+            .inner
+            .write(move |conn| conn.collect_garbage(&prefix, &roots, false))
+            .await;
+        if let Some(memory) = self.inner.memory() {
+            memory.clear();
+        }
+        report
+    }
+
+    /// Delete blobs that no longer back any value, returning how many were
+    /// deleted. See [`StoreOptions::blobs_above`].
+    pub async fn gc_blobs(&self) -> Result<u64, StoreError> {
+        self.ensure_writes()?;
+        self.inner.write(|conn| conn.gc_blobs()).await
+    }
+
+    /// Seal every value not already sealed with the current key of the
+    /// keyring set with [`StoreOptions::encryption`], including values
+    /// stored before encryption was turned on. Returns how many rows and
+    /// blobs were rewritten.
+    ///
+    /// Once it returns, older keys can be dropped from the keyring.
+    #[cfg(feature = "encryption")]
+    pub async fn reencrypt(&self) -> Result<u64, StoreError> {
+        self.ensure_writes()?;
+        self.inner
+            .write(|conn| conn.savepoint(|| conn.reencrypt()))
+            .await
+    }
+
+    /// Commit writes held back by [`StoreOptions::write_behind`], and wait
+    /// for values queued for the [`StoreOptions::remote`] cache to be sent.
+    /// A no-op otherwise.
+    pub async fn flush(&self) -> Result<(), StoreError> {
+        self.inner.flush().await?;
+        if let Some(remote) = self.inner.remote() {
+            remote.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Close the store, waiting until everything it wrote is committed and
+    /// its database file is closed, so that another store can open it.
+    ///
+    /// Clones and views of the store share its database, so while any of
+    /// them is left this only drops `self`. Dropping the last one closes
+    /// the database too, without waiting: the writes queued by then are
+    /// still committed, in the background.
+    pub async fn close(self) -> Result<(), StoreError> {
+        if let Some(remote) = self.inner.remote() {
+            remote.flush().await?;
+        }
+        if let Ok(db) = Arc::try_unwrap(self.inner) {
+            db.close().await;
+        }
+        Ok(())
+    }
+
+    /// Forget the panics recorded against `key`, lifting any quarantine set
+    /// by [`Builder::quarantine_after`].
+    ///
+    /// `key` is the full cache key, i.e. the namespace segments and params
+    /// joined with `","`.
+    pub async fn clear_panics(&self, key: impl AsRef<str>) -> Result<(), StoreError> {
+        self.ensure_writes()?;
+        self.inner.clear_panics(key.as_ref()).await
+    }
+
+    /// The number of recorded panics for `key` since its last success.
+    pub async fn panics(&self, key: impl AsRef<str>) -> Result<u32, StoreError> {
+        self.inner.fetch_panics(key.as_ref()).await
+    }
+
+    /// The [`Mode`] of this store view.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Override the [`Mode`] for this view and the views derived from it.
+    ///
+    /// ```rust
+    /// # async fn doc() -> Result<(), potency::StoreError> {
+    /// use potency::{Mode, Store};
+    ///
+    /// let store = Store::in_memory().await?;
+    /// let debug = store.namespace("flaky-api").with_mode(Mode::Bypass);
+    /// assert_eq!(store.mode(), Mode::ReadWrite);
+    /// assert_eq!(debug.mode(), Mode::Bypass);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    fn ensure_writes(&self) -> Result<(), StoreError> {
+        if self.mode.writes() {
+            Ok(())
+        } else {
+            WritesDisabledSnafu { mode: self.mode }.fail()
+        }
+    }
+
+    /// Use `spawner` to run background refreshes scheduled by
+    /// [`Builder::stale_while_revalidate`].
+    ///
+    /// Tasks are not `Send`, so the spawner should put them on a local
+    /// executor, e.g. `tokio::task::spawn_local` or a thread-local
+    /// `smol::LocalExecutor`.
+    pub fn with_spawner(
+        mut self,
+        spawner: impl Fn(BackgroundTask) + Send + std::marker::Sync + 'static,
+    ) -> Self {
+        self.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Attach a namespace segment to subsequent calls.
+    pub fn namespace(&self, namespace: impl AsRef<str>) -> Self {
+        let namespace = namespace.as_ref().to_string();
+        let mut store = self.clone();
+        log::trace!("store '{:?}' adding '{namespace}'", store.key);
+        store.key.push(namespace);
+        store
+    }
+
+    /// Begin a sync entry.
+    pub fn entry<F>(&self, f: F) -> Builder<'_, (), F> {
+        let _input: PhantomData<(Sync, ())> = PhantomData;
+        let fn_pair: FnPair<(), F, Sync> = FnPair { f, _input };
+        Builder {
+            store: self,
+            // Inherit the Store's accumulated namespace segments so the
+            // resulting cache key reflects both the namespace and the
+            // params added via `.param(...)`.
+            key: self.key.clone(),
+            input: (),
+            fn_pair,
+            opts: RunOptions::default(),
+        }
+    }
+
+    /// Begin an async entry.
+    pub fn entry_async<F>(&self, f: F) -> Builder<'_, (), F, Async> {
+        Builder {
+            store: self,
+            key: self.key.clone(),
+            input: (),
+            fn_pair: FnPair {
+                f,
+                _input: std::marker::PhantomData,
+            },
+            opts: RunOptions::default(),
+        }
+    }
+
+    /// Begin a durable side-effect entry. See [`effect::fs_effect`] for the
+    /// common filesystem case.
+    pub fn effect<E>(&self, effect: E) -> EffectBuilder<'_, E> {
+        EffectBuilder {
+            store: self,
+            key: self.key.clone(),
+            effect,
+            pinned: false,
+            tags: Vec::new(),
+        }
+    }
+}
+
+/// A key in the set of keys being refreshed, removed when dropped.
+struct InFlight {
+    refreshing: Arc<std::sync::Mutex<std::collections::HashSet<String>>>,
+    key: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        // UNWRAP: safe for the same reason as in `Store::spawn_refresh`.
+        self.refreshing.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Call `f` and drive its future, catching a panic from either.
+///
+/// Sync work runs inside `f()` itself, async work inside the future, so both
+/// need to be guarded.
+async fn catch_panic<T, Fut: Future<Output = T>>(f: impl FnOnce() -> Fut) -> Result<T, String> {
+    use futures_lite::FutureExt;
+    use std::panic::AssertUnwindSafe;
+
+    let fut = std::panic::catch_unwind(AssertUnwindSafe(f)).map_err(panic_message)?;
+    AssertUnwindSafe(fut)
+        .catch_unwind()
+        .await
+        .map_err(panic_message)
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "non-string panic payload".to_owned()
+    }
+}
 
 // ============================================================================
-// Effect builder (durable side-effects)
-// ============================================================================
+// Global store for `potency-macros`
+// ============================================================================
+
+static GLOBAL_STORE: std::sync::OnceLock<Store> = std::sync::OnceLock::new();
+
+/// Install the process-global [`Store`] used by `potency-macros`.
+///
+/// One-shot: subsequent calls return [`AlreadyInstalled`].
+pub fn install_global_store(store: Store) -> Result<&'static Store, AlreadyInstalled> {
+    GLOBAL_STORE.set(store).map_err(|_| AlreadyInstalled)?;
+    Ok(GLOBAL_STORE.get().unwrap())
+}
+
+/// Read the global store, if installed.
+pub fn global_store() -> Option<&'static Store> {
+    GLOBAL_STORE.get()
+}
+
+/// A function marked `#[durable]`, registered when the program is linked.
+///
+/// [`Roots::durable`] keeps the namespaces of all of them.
+#[derive(Debug)]
+pub struct DurableFn {
+    /// The function's name.
+    pub name: &'static str,
+    /// The namespace its results are stored under.
+    pub namespace: &'static str,
+}
+
+inventory::collect!(DurableFn);
+
+/// Every `#[durable]` function linked into the program.
+pub fn durable_fns() -> impl Iterator<Item = &'static DurableFn> {
+    inventory::iter::<DurableFn>.into_iter()
+}
+
+#[doc(hidden)]
+pub use inventory;
+
+/// Returned by [`install_global_store`] when a store was already installed.
+#[derive(Debug)]
+pub struct AlreadyInstalled;
+
+impl std::fmt::Display for AlreadyInstalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "potency: global store already installed")
+    }
+}
+
+impl std::error::Error for AlreadyInstalled {}
+
+// ============================================================================
+// Effect builder (durable side-effects)
+// ============================================================================
 
 /// A durable side-effecting operation.
 ///
@@ -442,6 +1615,8 @@ pub struct EffectBuilder<'a, E> {
     store: &'a Store,
     key: Vec<String>,
     effect: E,
+    pinned: bool,
+    tags: Vec<String>,
 }
 
 impl<'a, E> EffectBuilder<'a, E> {
@@ -454,6 +1629,19 @@ impl<'a, E> EffectBuilder<'a, E> {
         self.key.push(ns.as_ref().to_string());
         self
     }
+
+    /// Pin the manifest; see [`Builder::pin`].
+    pub fn pin(mut self) -> Self {
+        self.pinned = true;
+        self
+    }
+
+    /// Tag the manifest; see [`Builder::tag`]. Invalidating the tag
+    /// deletes the manifest, so the effect is produced again next run.
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.push(tag.into());
+        self
+    }
 }
 
 impl<E, Err> EffectBuilder<'_, E>
@@ -468,27 +1656,42 @@ where
     /// - **Miss:** stages, produces, commits, then records the manifest.
     ///
     /// **Nesting.** The `fresh_staging` / `produce` / `commit` phase runs
-    /// *without* the SQLite connection lock held, so an `Effect`'s
+    /// while no database work is in flight, so an `Effect`'s
     /// filesystem work can include nested durable calls (or other
     /// effects) without deadlocking. Note that two `Effect` runs sharing
     /// the same cache key from *different tasks* would still race on the
     /// staging directory; this design supports nesting in a single task,
     /// not concurrent same-key runs across tasks.
+    ///
+    /// **Modes.** In [`Mode::CacheOnly`] a verified hit is returned and
+    /// anything else is [`StoreError::NotCached`]; nothing is produced. In
+    /// [`Mode::ReadOnly`] and [`Mode::Bypass`] the effect may be produced
+    /// but its manifest is never recorded (and `Bypass` never looks).
     pub async fn run(self) -> Result<E::Manifest, EffectError> {
-        let Self { store, key, effect } = self;
+        let Self {
+            store,
+            key,
+            effect,
+            pinned,
+            tags,
+        } = self;
         let full_key = key.join(",");
-
-        // Step 1: brief lock to fetch.
-        let cached: Option<serde_json::Value> = {
-            let mut lock = store.inner.lock().await;
-            fetch_value(&mut lock, &full_key)
+        let mode = store.mode;
+        store.inner.depended_on(&full_key);
+
+        // Step 1: fetch.
+        let cached = if mode.reads() {
+            store
+                .inner
+                .fetch_value(&full_key)
                 .await
                 .map_err(EffectError::Store)?
+        } else {
+            None
         };
 
-        if let Some(json_value) = cached {
-            let manifest: E::Manifest = serde_json::from_value(json_value)
-                .map_err(|e| EffectError::Store(StoreError::Json { source: e }))?;
+        if let Some(row) = cached {
+            let manifest: E::Manifest = row.decode().map_err(EffectError::Store)?;
 
             // Step 2: verify outside the lock — verify is filesystem-only.
             if effect
@@ -497,38 +1700,68 @@ where
                 .map_err(|e| EffectError::Store(e.into()))?
             {
                 log::trace!("{full_key:?} effect cache hit (verified)");
+                if mode.writes() {
+                    store.inner.record_hit(&full_key);
+                }
                 return Ok(manifest);
             }
-            // Stale: brief lock to delete the entry.
+            // Stale: delete the entry.
             log::trace!("{full_key:?} effect cache stale; invalidating");
-            let mut lock = store.inner.lock().await;
-            delete_value(&mut lock, &full_key)
-                .await
-                .map_err(EffectError::Store)?;
+            if mode.writes() {
+                store
+                    .inner
+                    .delete_value(&full_key)
+                    .await
+                    .map_err(EffectError::Store)?;
+            }
+        }
+        if !mode.computes() {
+            return Err(EffectError::Store(StoreError::NotCached { key: full_key }));
         }
 
-        // Step 3: filesystem work — NO LOCK held. This allows effects to
-        // themselves be invoked from inside another durable call without
-        // deadlocking on the SQLite connection.
+        // Step 3: filesystem work — no database job in flight. This allows
+        // effects to themselves be invoked from inside another durable call
+        // without deadlocking on the SQLite connection. The durable calls
+        // `produce` makes are recorded as the effect's dependencies.
         log::trace!("{full_key:?} effect computing");
+        let started = std::time::Instant::now();
         let staging = effect
             .fresh_staging(&full_key)
             .await
             .map_err(|e| EffectError::Store(e.into()))?;
-        let manifest = effect
-            .produce(&staging)
+        let computation = store.inner.computation();
+        let manifest = deps::track(computation.clone(), || effect.produce(&staging))()
             .await
             .map_err(|e| EffectError::Store(e.into()))?;
         effect
             .commit(&staging, &manifest)
             .await
             .map_err(|e| EffectError::Store(e.into()))?;
+        let compute_duration = Some(started.elapsed());
+
+        if !mode.writes() {
+            log::trace!("{full_key:?} effect produced, not recorded in {mode:?} mode");
+            return Ok(manifest);
+        }
 
-        // Step 4: brief lock to store the manifest.
-        let mut lock = store.inner.lock().await;
-        let json_value = serde_json::to_value(manifest.clone())
-            .map_err(|e| EffectError::Store(StoreError::Json { source: e }))?;
-        store_value(&mut lock, &full_key, &json_value)
+        // Step 4: store the manifest.
+        let encoding = store.encoding;
+        let encoded = encoding
+            .codec
+            .encode(&manifest)
+            .map_err(EffectError::Store)?;
+        let deps = computation.take();
+        store
+            .inner
+            .write(move |conn| {
+                let stamps = Stamps {
+                    pinned,
+                    tags,
+                    ..Stamps::new(None, compute_duration)
+                };
+                conn.store_value(&full_key, encoding, &encoded, &stamps)?;
+                conn.depend(&full_key, &deps)
+            })
             .await
             .map_err(EffectError::Store)?;
         Ok(manifest)
@@ -824,5 +2057,1385 @@ mod tests {
         let n = calls.get();
         assert!((1..=2).contains(&n), "unexpected compute count {n}");
     }
+
+    /// A panicking function is reported as `Panicked`, counted, and
+    /// quarantined once it reaches the limit.
+    #[test]
+    fn panics_are_caught_and_quarantined() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            let calls = Counter::default();
+            let run = |calls: Counter| {
+                let store = store.clone();
+                async move {
+                    store
+                        .namespace("boom")
+                        .entry(move |x: u32| -> Result<u32, StoreError> {
+                            let _ = calls.bump();
+                            panic!("bad input {x}");
+                        })
+                        .param(1u32)
+                        .quarantine_after(2)
+                        .run()
+                        .await
+                }
+            };
+
+            for _ in 0..2 {
+                match run(calls.clone()).await {
+                    Err(StoreError::Panicked { message }) => assert_eq!(message, "bad input 1"),
+                    other => panic!("expected Panicked, got {other:?}"),
+                }
+            }
+            assert_eq!(store.panics("boom,1").await.unwrap(), 2);
+
+            let result = run(calls.clone()).await;
+            assert!(
+                matches!(result, Err(StoreError::Quarantined { panics: 2, .. })),
+                "expected Quarantined, got {result:?}"
+            );
+            assert_eq!(calls.get(), 2, "quarantined key must not run");
+
+            store.clear_panics("boom,1").await.unwrap();
+            assert!(matches!(
+                run(calls.clone()).await,
+                Err(StoreError::Panicked { .. })
+            ));
+            assert_eq!(calls.get(), 3);
+        });
+    }
+
+    /// A success after caught panics clears the recorded attempts.
+    #[test]
+    fn success_clears_recorded_panics() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            let result = store
+                .entry_async(|_: u32| async move {
+                    panic!("async boom");
+                    #[allow(unreachable_code)]
+                    Ok::<u32, StoreError>(0)
+                })
+                .param(5u32)
+                .catch_panics()
+                .run()
+                .await;
+            assert!(matches!(result, Err(StoreError::Panicked { .. })));
+            assert_eq!(store.panics("5").await.unwrap(), 1);
+
+            let n = store
+                .entry(|x: u32| Ok::<u32, StoreError>(x))
+                .param(5u32)
+                .catch_panics()
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(n, 5);
+            assert_eq!(store.panics("5").await.unwrap(), 0);
+        });
+    }
+
+    /// Outputs rejected by `cache_if` are returned but not stored.
+    #[test]
+    fn cache_if_skips_rejected_outputs() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            let calls = Counter::default();
+            let fetch = |calls: Counter, complete: bool| {
+                let store = store.clone();
+                async move {
+                    store
+                        .namespace("list")
+                        .entry(move |n: u32| -> Result<Vec<u32>, StoreError> {
+                            let _ = calls.bump();
+                            Ok(if complete { (0..n).collect() } else { vec![] })
+                        })
+                        .param(3u32)
+                        .cache_if(|items: &Vec<u32>| !items.is_empty())
+                        .run()
+                        .await
+                        .unwrap()
+                }
+            };
+
+            assert!(fetch(calls.clone(), false).await.is_empty());
+            assert!(fetch(calls.clone(), false).await.is_empty());
+            assert_eq!(calls.get(), 2, "rejected output must not be cached");
+
+            assert_eq!(fetch(calls.clone(), true).await, vec![0, 1, 2]);
+            assert_eq!(fetch(calls.clone(), false).await, vec![0, 1, 2]);
+            assert_eq!(calls.get(), 3, "accepted output is cached");
+        });
+    }
+
+    /// An entry past its TTL is recomputed.
+    #[test]
+    fn ttl_expiry_recomputes() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            let calls = Counter::default();
+            let run = |calls: Counter| {
+                let store = store.clone();
+                async move {
+                    store
+                        .entry(move |x: u32| -> Result<u32, StoreError> { Ok(x + calls.bump()) })
+                        .param(10u32)
+                        .ttl(std::time::Duration::from_millis(20))
+                        .run()
+                        .await
+                        .unwrap()
+                }
+            };
+            assert_eq!(run(calls.clone()).await, 11);
+            assert_eq!(run(calls.clone()).await, 11, "fresh entry is a hit");
+            smol::Timer::after(std::time::Duration::from_millis(30)).await;
+            assert_eq!(run(calls.clone()).await, 12, "expired entry recomputes");
+            assert_eq!(calls.get(), 2);
+        });
+    }
+
+    thread_local! {
+        static BACKGROUND: std::cell::RefCell<Vec<BackgroundTask>> = Default::default();
+    }
+
+    /// A stale hit inside the window returns the old value at once and
+    /// schedules exactly one refresh, however many stale hits arrive.
+    #[test]
+    fn stale_while_revalidate_refreshes_once_in_background() {
+        smol::block_on(async {
+            let store = Store::in_memory()
+                .await
+                .unwrap()
+                .with_spawner(|task| BACKGROUND.with(|tasks| tasks.borrow_mut().push(task)));
+            let calls = Counter::default();
+            let run = |calls: Counter| {
+                let store = store.clone();
+                async move {
+                    store
+                        .entry(move |x: u32| -> Result<u32, StoreError> { Ok(x + calls.bump()) })
+                        .param(10u32)
+                        .ttl(std::time::Duration::from_millis(20))
+                        .stale_while_revalidate(std::time::Duration::from_secs(60))
+                        .run()
+                        .await
+                        .unwrap()
+                }
+            };
+            assert_eq!(run(calls.clone()).await, 11);
+            smol::Timer::after(std::time::Duration::from_millis(30)).await;
+
+            assert_eq!(run(calls.clone()).await, 11, "stale value served");
+            assert_eq!(run(calls.clone()).await, 11, "stale value served");
+            let tasks = BACKGROUND.with(|tasks| std::mem::take(&mut *tasks.borrow_mut()));
+            assert_eq!(tasks.len(), 1, "one refresh per stale key");
+            assert_eq!(calls.get(), 1, "refresh has not run yet");
+
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(calls.get(), 2);
+            assert_eq!(
+                run(calls.clone()).await,
+                12,
+                "refreshed value replaced the row"
+            );
+        });
+    }
+
+    /// A refresh task dropped before it finishes doesn't stop the next
+    /// stale hit from scheduling another.
+    #[test]
+    fn dropped_refresh_is_rescheduled() {
+        smol::block_on(async {
+            let store = Store::in_memory()
+                .await
+                .unwrap()
+                .with_spawner(|task| BACKGROUND.with(|tasks| tasks.borrow_mut().push(task)));
+            let calls = Counter::default();
+            let run = |calls: Counter| {
+                let store = store.clone();
+                async move {
+                    store
+                        .entry(move |x: u32| -> Result<u32, StoreError> { Ok(x + calls.bump()) })
+                        .param(10u32)
+                        .ttl(std::time::Duration::from_millis(20))
+                        .stale_while_revalidate(std::time::Duration::from_secs(60))
+                        .run()
+                        .await
+                        .unwrap()
+                }
+            };
+            assert_eq!(run(calls.clone()).await, 11);
+            smol::Timer::after(std::time::Duration::from_millis(30)).await;
+
+            assert_eq!(run(calls.clone()).await, 11, "stale value served");
+            let tasks = BACKGROUND.with(|tasks| std::mem::take(&mut *tasks.borrow_mut()));
+            assert_eq!(tasks.len(), 1);
+            drop(tasks);
+
+            assert_eq!(run(calls.clone()).await, 11, "stale value served");
+            let tasks = BACKGROUND.with(|tasks| std::mem::take(&mut *tasks.borrow_mut()));
+            assert_eq!(tasks.len(), 1, "the dropped refresh released its key");
+            for task in tasks {
+                task.await;
+            }
+            assert_eq!(calls.get(), 2);
+        });
+    }
+
+    /// `peek`, `is_cached`, `run_if_missing_else_error` and `refresh` all
+    /// address the same slot as `run`.
+    #[test]
+    fn builder_modes_share_run_key() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap().namespace("modes");
+            let calls = Counter::default();
+            let builder = |calls: Counter| {
+                store
+                    .entry(move |x: u32| -> Result<u32, StoreError> { Ok(x * calls.bump()) })
+                    .param(7u32)
+            };
+
+            assert!(!builder(calls.clone()).is_cached().await.unwrap());
+            assert_eq!(builder(calls.clone()).peek().await.unwrap(), None);
+            let result = builder(calls.clone()).run_if_missing_else_error().await;
+            assert!(matches!(result, Err(StoreError::NotCached { key }) if key == "modes,7"));
+            assert_eq!(calls.get(), 0, "cache-only modes never run");
+
+            assert_eq!(builder(calls.clone()).run().await.unwrap(), 7);
+            assert!(builder(calls.clone()).is_cached().await.unwrap());
+            assert_eq!(builder(calls.clone()).peek().await.unwrap(), Some(7));
+
+            assert_eq!(builder(calls.clone()).refresh().await.unwrap(), 14);
+            assert_eq!(calls.get(), 2, "refresh always runs");
+            assert_eq!(
+                builder(calls.clone())
+                    .run_if_missing_else_error()
+                    .await
+                    .unwrap(),
+                14
+            );
+            assert_eq!(builder(calls.clone()).run().await.unwrap(), 14);
+            assert_eq!(calls.get(), 2);
+        });
+    }
+
+    /// Each mode reads, computes and writes as documented, and a view's
+    /// override does not leak into the parent store.
+    #[test]
+    fn store_modes() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            let calls = Counter::default();
+            let run = |store: Store, calls: Counter, x: u32| async move {
+                store
+                    .entry(move |x: u32| -> Result<u32, StoreError> {
+                        let _ = calls.bump();
+                        Ok(x + 1)
+                    })
+                    .param(x)
+                    .run()
+                    .await
+            };
+            assert_eq!(run(store.clone(), calls.clone(), 1).await.unwrap(), 2);
+
+            let read_only = store.clone().with_mode(Mode::ReadOnly);
+            assert_eq!(run(read_only.clone(), calls.clone(), 1).await.unwrap(), 2);
+            assert_eq!(calls.get(), 1, "read-only serves hits");
+            assert_eq!(run(read_only.clone(), calls.clone(), 2).await.unwrap(), 3);
+            assert_eq!(run(read_only.clone(), calls.clone(), 2).await.unwrap(), 3);
+            assert_eq!(calls.get(), 3, "read-only never stores misses");
+            assert!(matches!(
+                read_only.clear_panics("2").await,
+                Err(StoreError::WritesDisabled {
+                    mode: Mode::ReadOnly
+                })
+            ));
+
+            let cache_only = store.clone().with_mode(Mode::CacheOnly);
+            assert_eq!(run(cache_only.clone(), calls.clone(), 1).await.unwrap(), 2);
+            let result = run(cache_only.clone(), calls.clone(), 3).await;
+            assert!(matches!(result, Err(StoreError::NotCached { key }) if key == "3"));
+            assert_eq!(calls.get(), 3, "cache-only never computes");
+
+            let bypass = store.clone().with_mode(Mode::Bypass);
+            assert_eq!(run(bypass, calls.clone(), 1).await.unwrap(), 2);
+            assert_eq!(calls.get(), 4, "bypass ignores hits");
+
+            assert_eq!(store.mode(), Mode::ReadWrite);
+            assert_eq!(run(store.clone(), calls.clone(), 2).await.unwrap(), 3);
+            assert_eq!(calls.get(), 5, "read-write stores the miss");
+        });
+    }
+
+    /// Two stores with different table names share a file without seeing
+    /// each other's rows, and a read-only open serves existing rows.
+    #[test]
+    fn open_with_options() {
+        smol::block_on(async {
+            let dir = std::env::temp_dir().join("potency-open-with-options");
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let db = dir.join("state.db");
+            let options = || {
+                StoreOptions::new()
+                    .journal_mode(JournalMode::Wal)
+                    .synchronous(Synchronous::Normal)
+                    .busy_timeout(std::time::Duration::from_secs(1))
+            };
+            let a = Store::open_with(&db, options().table_name("cache_a"))
+                .await
+                .unwrap();
+            let b = Store::open_with(&db, options().table_name("cache_b"))
+                .await
+                .unwrap();
+            let run = |store: Store, n: u32| async move {
+                store
+                    .entry(move |x: u32| Ok::<u32, StoreError>(x + n))
+                    .param(1u32)
+                    .run()
+                    .await
+                    .unwrap()
+            };
+            assert_eq!(run(a.clone(), 1).await, 2);
+            assert_eq!(run(b.clone(), 2).await, 3, "tables are independent");
+            assert_eq!(run(a.clone(), 5).await, 2);
+
+            let read_only = Store::open_with(
+                &db,
+                StoreOptions::new().table_name("cache_a").read_only(true),
+            )
+            .await
+            .unwrap();
+            assert_eq!(read_only.mode(), Mode::ReadOnly);
+            assert_eq!(run(read_only.clone(), 5).await, 2, "read-only serves hits");
+
+            let result = Store::open_with(&db, StoreOptions::new().table_name("x; DROP")).await;
+            assert!(matches!(result, Err(StoreError::InvalidTableName { .. })));
+            let _ = std::fs::remove_dir_all(&dir);
+        });
+    }
+
+    /// With a reader pool, hits are served from readers and still see rows
+    /// just written through the writer.
+    #[test]
+    fn reader_pool_sees_writes() {
+        let dir = std::env::temp_dir().join("potency-reader-pool");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = smol::block_on(Store::open_with(
+            dir.join("state.db"),
+            StoreOptions::new().readers(2),
+        ))
+        .unwrap();
+        assert!(store.inner.has_readers());
+        let calls = Counter::default();
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                let calls = calls.clone();
+                std::thread::spawn(move || {
+                    smol::block_on(async move {
+                        for x in 0..20u32 {
+                            let calls = calls.clone();
+                            let n = store
+                                .entry(move |x: u32| -> Result<u32, StoreError> {
+                                    let _ = calls.bump();
+                                    Ok(x * 3)
+                                })
+                                .param(x)
+                                .run()
+                                .await
+                                .unwrap();
+                            assert_eq!(n, x * 3);
+                        }
+                    })
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        // Racing misses may compute twice, but every key is stored once
+        // and later calls are hits.
+        let before = calls.get();
+        assert!(
+            (20..=80).contains(&before),
+            "unexpected compute count {before}"
+        );
+        smol::block_on(async {
+            for x in 0..20u32 {
+                let is_cached = store
+                    .entry(|x: u32| Ok::<u32, StoreError>(x))
+                    .param(x)
+                    .is_cached()
+                    .await
+                    .unwrap();
+                assert!(is_cached);
+            }
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_behind_holds_writes_until_flushed() {
+        let dir = std::env::temp_dir().join("potency-write-behind");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.db");
+        let store = smol::block_on(Store::open_with(
+            &path,
+            StoreOptions::new()
+                .readers(2)
+                .write_behind(std::time::Duration::from_secs(3600)),
+        ))
+        .unwrap();
+        let committed = || {
+            let connection = sqlite::Connection::open(&path).unwrap();
+            let mut statement = connection.prepare("SELECT count(*) FROM potency").unwrap();
+            statement.next().unwrap();
+            statement.read::<i64, _>(0).unwrap()
+        };
+
+        smol::block_on(async {
+            for x in 0..10u32 {
+                store
+                    .entry(|x: u32| Ok::<u32, StoreError>(x))
+                    .param(x)
+                    .run()
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(committed(), 0);
+            // Readers see the uncommitted writes.
+            for x in 0..10u32 {
+                let value = store
+                    .entry(|_: u32| -> Result<u32, StoreError> { panic!("should be a hit") })
+                    .param(x)
+                    .peek()
+                    .await
+                    .unwrap();
+                assert_eq!(value, Some(x));
+            }
+            store.flush().await.unwrap();
+            assert_eq!(committed(), 10);
+        });
+        smol::block_on(store.close()).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn codecs_are_recorded_per_row() {
+        let dir = std::env::temp_dir().join("potency-codec-per-row");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.db");
+        smol::block_on(async {
+            let store = Store::open(&path).await.unwrap();
+            let json = store
+                .entry(|x: u32| Ok::<Vec<u8>, StoreError>(vec![x as u8; 4]))
+                .param(1u32)
+                .run()
+                .await
+                .unwrap();
+            store.close().await.unwrap();
+
+            let options = StoreOptions::new().codec(Codec::MessagePack);
+            let store = Store::open_with(&path, options).await.unwrap();
+            let hit = store
+                .entry(|_: u32| -> Result<Vec<u8>, StoreError> { panic!("should be a hit") })
+                .param(1u32)
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(hit, json);
+            let fresh = store
+                .entry(|x: u32| Ok::<Vec<u8>, StoreError>(vec![x as u8; 4]))
+                .param(2u32)
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(fresh, vec![2; 4]);
+        });
+        let connection = sqlite::Connection::open(&path).unwrap();
+        let mut statement = connection
+            .prepare("SELECT codec FROM potency ORDER BY key")
+            .unwrap();
+        let codecs: Vec<String> = std::iter::from_fn(|| {
+            (statement.next().unwrap() == sqlite::State::Row)
+                .then(|| statement.read::<String, _>(0).unwrap())
+        })
+        .collect();
+        assert_eq!(codecs, ["json", "msgpack"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stats_count_entries() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            assert_eq!(store.stats().await.unwrap(), Stats::default());
+            for x in 0..3u32 {
+                store
+                    .entry(|x: u32| Ok::<String, StoreError>(x.to_string()))
+                    .param(x)
+                    .run()
+                    .await
+                    .unwrap();
+            }
+            let stats = store.stats().await.unwrap();
+            assert_eq!(stats.entries, 3);
+            assert_eq!(stats.compressed_entries, 0);
+            // Three JSON strings: `"0"`, `"1"`, `"2"`.
+            assert_eq!(stats.stored_bytes, 9);
+            assert_eq!(stats.compression_ratio(), 1.0);
+        });
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn large_values_are_compressed() {
+        smol::block_on(async {
+            let options = StoreOptions::new()
+                .compression(Compression::Lz4)
+                .compress_above(1024);
+            let store = Store::open_with(":memory:", options).await.unwrap();
+            let text = |n: u32| Ok::<String, StoreError>("potency ".repeat(n as usize));
+            for (n, compression) in [(1000, None), (10, None), (2000, Some(Compression::None))] {
+                let mut builder = store.entry(text).param(n);
+                if let Some(compression) = compression {
+                    builder = builder.compression(compression);
+                }
+                assert_eq!(builder.run().await.unwrap(), text(n).unwrap());
+                let hit = store
+                    .entry(|_: u32| -> Result<String, StoreError> { panic!("should be a hit") })
+                    .param(n)
+                    .run()
+                    .await
+                    .unwrap();
+                assert_eq!(hit, text(n).unwrap());
+            }
+            let stats = store.stats().await.unwrap();
+            assert_eq!(stats.entries, 3);
+            assert_eq!(stats.compressed_entries, 1);
+            assert!(stats.compression_ratio() > 1.2, "{stats:?}");
+        });
+    }
+
+    #[test]
+    fn large_values_share_blobs() {
+        smol::block_on(async {
+            let options = StoreOptions::new().blobs_above(1024);
+            let store = Store::open_with(":memory:", options).await.unwrap();
+            let big = |_: u32| Ok::<String, StoreError>("potency ".repeat(1000));
+            let small = |x: u32| Ok::<String, StoreError>(x.to_string());
+            for x in 0..3u32 {
+                assert_eq!(
+                    store.entry(big).param(x).run().await.unwrap(),
+                    big(x).unwrap()
+                );
+            }
+            store.entry(small).param(3u32).run().await.unwrap();
+            let stats = store.stats().await.unwrap();
+            assert_eq!((stats.entries, stats.blobs), (4, 1));
+            assert!(stats.compression_ratio() > 2.5, "{stats:?}");
+
+            // Replacing two of the three references keeps the blob alive.
+            for x in 0..2u32 {
+                store.entry(small).param(x).refresh().await.unwrap();
+            }
+            assert_eq!(store.gc_blobs().await.unwrap(), 0);
+            let hit = store
+                .entry(|_: u32| -> Result<String, StoreError> { panic!("should be a hit") })
+                .param(2u32)
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(hit, big(2).unwrap());
+
+            store.entry(small).param(2u32).refresh().await.unwrap();
+            assert_eq!(store.gc_blobs().await.unwrap(), 1);
+            assert_eq!(store.stats().await.unwrap().blobs, 0);
+        });
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn values_are_encrypted_and_rotated() {
+        let dir = std::env::temp_dir().join("potency-encryption");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.db");
+        let secret = |x: u32| Ok::<String, StoreError>(format!("token-{x}-").repeat(x as usize));
+        let open = |keyring: Option<Keyring>| {
+            let mut options = StoreOptions::new().blobs_above(1024);
+            if let Some(keyring) = keyring {
+                options = options.encryption(keyring);
+            }
+            smol::block_on(Store::open_with(&path, options)).unwrap()
+        };
+        let assert_hits = |store: &Store| {
+            smol::block_on(async {
+                for x in [1, 500] {
+                    let hit = store
+                        .entry(|_: u32| -> Result<String, StoreError> { panic!("should be a hit") })
+                        .param(x)
+                        .run()
+                        .await
+                        .unwrap();
+                    assert_eq!(hit, secret(x).unwrap());
+                }
+            })
+        };
+
+        // A plaintext row and a plaintext blob, from before encryption.
+        let store = open(None);
+        smol::block_on(async {
+            for x in [1, 500] {
+                store.entry(secret).param(x).run().await.unwrap();
+            }
+        });
+        smol::block_on(store.close()).unwrap();
+
+        let store = open(Some(Keyring::new("a", [1; 32])));
+        assert_hits(&store);
+        assert_eq!(smol::block_on(store.reencrypt()).unwrap(), 2);
+        smol::block_on(store.close()).unwrap();
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(8).any(|w| w == b"token-1-"));
+
+        let store = open(Some(Keyring::new("b", [2; 32]).with_old_key("a", [1; 32])));
+        assert_hits(&store);
+        assert_eq!(smol::block_on(store.reencrypt()).unwrap(), 2);
+        assert_eq!(smol::block_on(store.reencrypt()).unwrap(), 0);
+        smol::block_on(store.close()).unwrap();
+
+        let store = open(Some(Keyring::new("b", [2; 32])));
+        assert_hits(&store);
+        smol::block_on(store.close()).unwrap();
+        let store = open(None);
+        let result = smol::block_on(store.entry(secret).param(1u32).peek());
+        assert!(matches!(result, Err(StoreError::UnknownKey { .. })));
+        smol::block_on(store.close()).unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn hashed_keys_hide_params() {
+        smol::block_on(async {
+            let keyring = Keyring::new("a", [1; 32]).hash_keys([9; 32]);
+            let options = StoreOptions::new().encryption(keyring);
+            let store = Store::open_with(":memory:", options).await.unwrap();
+            let calls = Counter::default();
+            for _ in 0..2 {
+                let calls = calls.clone();
+                let value = store
+                    .namespace("users")
+                    .entry(move |email: String| {
+                        calls.bump();
+                        Ok::<usize, StoreError>(email.len())
+                    })
+                    .param("someone@example.com".to_owned())
+                    .run()
+                    .await
+                    .unwrap();
+                assert_eq!(value, 19);
+            }
+            assert_eq!(calls.get(), 1);
+            let keys = store
+                .inner
+                .read(|conn| {
+                    let mut statement = conn.connection().prepare("SELECT key FROM potency")?;
+                    let mut keys = vec![];
+                    while let sqlite::State::Row = statement.next()? {
+                        keys.push(statement.read::<String, _>(0)?);
+                    }
+                    Ok(keys)
+                })
+                .await
+                .unwrap();
+            assert_eq!(keys.len(), 1);
+            assert!(!keys[0].contains("example"), "{keys:?}");
+
+            use futures_lite::StreamExt;
+            let listed: Vec<String> = store.keys().try_collect().await.unwrap();
+            assert_eq!(listed, keys);
+            let mut users = store.namespace("users").keys();
+            assert!(matches!(
+                users.next().await,
+                Some(Err(StoreError::HashedKeys))
+            ));
+            assert!(users.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn entry_info_records_hits_and_durations() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            store
+                .namespace("info")
+                .entry_async(|x: u32| async move {
+                    smol::Timer::after(std::time::Duration::from_millis(20)).await;
+                    Ok::<u32, StoreError>(x)
+                })
+                .param(7u32)
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(store.entry_info("nope").await.unwrap(), None);
+
+            let info = store.entry_info("info,7").await.unwrap().unwrap();
+            assert_eq!(info.hit_count, 0);
+            assert_eq!(info.last_accessed_at, None);
+            assert!(info.created_at.is_some());
+            assert!(info.compute_duration.unwrap() >= std::time::Duration::from_millis(20));
+            assert_eq!(
+                info.potency_version.as_deref(),
+                Some(env!("CARGO_PKG_VERSION"))
+            );
+
+            for _ in 0..3 {
+                store
+                    .namespace("info")
+                    .entry(|_: u32| -> Result<u32, StoreError> { panic!("should be a hit") })
+                    .param(7u32)
+                    .run()
+                    .await
+                    .unwrap();
+            }
+            // Hits are recorded on the writer, ahead of this lookup.
+            let info = store.entry_info("info,7").await.unwrap().unwrap();
+            assert_eq!(info.hit_count, 3);
+            assert!(info.last_accessed_at >= info.created_at);
+            assert!(info.time_saved().unwrap() >= std::time::Duration::from_millis(60));
+        });
+    }
+
+    #[test]
+    fn iter_lists_namespaces_in_pages() {
+        use futures_lite::StreamExt;
+
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            let squares = store.namespace("squares");
+            // More than one page of keys.
+            for x in 0..300u32 {
+                squares
+                    .entry(|x: u32| Ok::<u32, StoreError>(x * x))
+                    .param(x)
+                    .run()
+                    .await
+                    .unwrap();
+            }
+            store
+                .namespace("squares2")
+                .entry(|| Ok::<String, StoreError>("not a square".into()))
+                .run()
+                .await
+                .unwrap();
+            store
+                .namespace("short")
+                .entry(|x: u32| Ok::<u32, StoreError>(x))
+                .param(1u32)
+                .ttl(std::time::Duration::ZERO)
+                .run()
+                .await
+                .unwrap();
+
+            let keys: Vec<String> = squares.keys().try_collect().await.unwrap();
+            assert_eq!(keys.len(), 300);
+            assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(keys.iter().all(|key| key.starts_with("squares,")));
+            assert_eq!(store.keys().count().await, 302);
+
+            let values: Vec<(String, u32)> = squares.iter_values().try_collect().await.unwrap();
+            assert_eq!(values.len(), 300);
+            assert!(values.contains(&("squares,12".to_owned(), 144)));
+
+            // Expired entries are listed, but their values are not.
+            let (key, info) = store
+                .namespace("short")
+                .iter()
+                .next()
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(key, "short,1");
+            assert!(info.expires_at.is_some());
+            let short = store.namespace("short").iter_values::<u32>();
+            assert_eq!(short.count().await, 0);
+        });
+    }
+
+    #[test]
+    fn export_import_round_trips() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            for x in 0..3u32 {
+                store
+                    .namespace("double")
+                    .entry(|x: u32| Ok::<u32, StoreError>(x * 2))
+                    .param(x)
+                    .run()
+                    .await
+                    .unwrap();
+            }
+            store
+                .namespace("other")
+                .entry(|| Ok::<String, StoreError>("left out".into()))
+                .run()
+                .await
+                .unwrap();
+            store
+                .namespace("double")
+                .entry(|_: u32| -> Result<u32, StoreError> { panic!("should be a hit") })
+                .param(1u32)
+                .run()
+                .await
+                .unwrap();
+
+            let mut exported = Vec::new();
+            let count = store
+                .namespace("double")
+                .export(&mut exported)
+                .await
+                .unwrap();
+            assert_eq!(count, 3);
+            let text = String::from_utf8(exported.clone()).unwrap();
+            assert_eq!(text.lines().count(), 3);
+            assert!(
+                text.contains(r#""key":"double,1","codec":"json","value":2"#),
+                "{text}"
+            );
+
+            let copy = Store::in_memory().await.unwrap();
+            let report = copy
+                .import(exported.as_slice(), ConflictPolicy::Fail)
+                .await
+                .unwrap();
+            assert_eq!(report.imported, 3);
+            let value = copy
+                .namespace("double")
+                .entry(|_: u32| -> Result<u32, StoreError> { panic!("should be imported") })
+                .param(2u32)
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(value, 4);
+            let (original, imported) = (
+                store.entry_info("double,1").await.unwrap().unwrap(),
+                copy.entry_info("double,1").await.unwrap().unwrap(),
+            );
+            assert_eq!(imported.hit_count, 1);
+            assert_eq!(imported.created_at, original.created_at);
+            assert_eq!(imported.compute_duration, original.compute_duration);
+
+            // A conflict under `Fail` imports nothing, not even new keys.
+            let mut conflicting = br#"{"key":"new","codec":"json","value":0}"#.to_vec();
+            conflicting.push(b'\n');
+            conflicting.extend_from_slice(&exported);
+            let error = copy
+                .import(conflicting.as_slice(), ConflictPolicy::Fail)
+                .await;
+            assert!(matches!(error, Err(StoreError::ImportConflict { .. })));
+            assert_eq!(copy.entry_info("new").await.unwrap(), None);
+
+            let report = copy
+                .import(conflicting.as_slice(), ConflictPolicy::SkipExisting)
+                .await
+                .unwrap();
+            assert_eq!((report.imported, report.skipped), (1, 3));
+            let report = copy
+                .import(conflicting.as_slice(), ConflictPolicy::Overwrite)
+                .await
+                .unwrap();
+            assert_eq!((report.imported, report.overwritten), (4, 4));
+
+            let error = copy
+                .import(&b"\n{\"key\": 1}\n"[..], ConflictPolicy::Overwrite)
+                .await;
+            assert!(matches!(
+                error,
+                Err(StoreError::InvalidImport { line: 2, .. })
+            ));
+
+            // Batches already written are undone by a bad line after them.
+            let mut long = Vec::new();
+            for i in 0..db::IMPORT_BATCH * 2 {
+                long.extend_from_slice(
+                    format!(r#"{{"key":"long,{i}","codec":"json","value":{i}}}"#).as_bytes(),
+                );
+                long.push(b'\n');
+            }
+            long.extend_from_slice(b"not json\n");
+            let error = copy.import(long.as_slice(), ConflictPolicy::Fail).await;
+            assert!(matches!(error, Err(StoreError::InvalidImport { .. })));
+            assert_eq!(copy.entry_info("long,0").await.unwrap(), None);
+            long.truncate(long.len() - b"not json\n".len());
+            let report = copy.import(long.as_slice(), ConflictPolicy::Fail).await;
+            assert_eq!(report.unwrap().imported, db::IMPORT_BATCH as u64 * 2);
+        });
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn export_keeps_other_codecs_as_hex() {
+        smol::block_on(async {
+            let options = StoreOptions::new().codec(Codec::MessagePack);
+            let store = Store::open_with(":memory:", options).await.unwrap();
+            store
+                .entry(|| Ok::<Vec<u8>, StoreError>(vec![1, 2, 3]))
+                .run()
+                .await
+                .unwrap();
+            let mut exported = Vec::new();
+            store.export(&mut exported).await.unwrap();
+            let text = String::from_utf8(exported.clone()).unwrap();
+            assert!(
+                text.contains(r#""codec":"msgpack","bytes":"93010203""#),
+                "{text}"
+            );
+
+            let copy = Store::in_memory().await.unwrap();
+            copy.import(exported.as_slice(), ConflictPolicy::Fail)
+                .await
+                .unwrap();
+            let value = copy
+                .entry(|| -> Result<Vec<u8>, StoreError> { panic!("should be imported") })
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(value, vec![1, 2, 3]);
+        });
+    }
+
+    /// A backup taken while another thread writes is a complete, openable
+    /// store.
+    #[test]
+    fn backup_while_writing() {
+        let dir = std::env::temp_dir().join("potency-backup");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = StoreOptions::new().readers(1);
+        let store = smol::block_on(Store::open_with(dir.join("state.db"), options)).unwrap();
+        let fill = |store: Store, namespace: &'static str| {
+            smol::block_on(async move {
+                // Enough 100 KB values for several backup steps.
+                for x in 0..60u32 {
+                    store
+                        .namespace(namespace)
+                        .entry(|x: u32| Ok::<String, StoreError>(x.to_string().repeat(100_000)))
+                        .param(x)
+                        .run()
+                        .await
+                        .unwrap();
+                }
+            })
+        };
+        fill(store.clone(), "before");
+
+        let writer = std::thread::spawn({
+            let store = store.clone();
+            move || fill(store, "during")
+        });
+        let steps = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let done = smol::block_on(store.backup_to(dir.join("backup.db"), {
+            let steps = steps.clone();
+            move |progress| steps.lock().unwrap().push(progress)
+        }))
+        .unwrap();
+        writer.join().unwrap();
+
+        let steps = steps.lock().unwrap();
+        assert!(steps.len() > 1, "{steps:?}");
+        assert_eq!(steps.last(), Some(&done));
+        assert_eq!(done.fraction(), 1.0);
+        assert!(!dir.join("backup.db.partial").exists());
+
+        smol::block_on(async {
+            use futures_lite::StreamExt;
+            let restored = Store::open(dir.join("backup.db")).await.unwrap();
+            assert_eq!(restored.namespace("before").keys().count().await, 60);
+            let during = restored.namespace("during").keys().count().await;
+            assert!(during <= 60);
+            let value = restored
+                .namespace("before")
+                .entry(|_: u32| -> Result<String, StoreError> { panic!("should be restored") })
+                .param(7u32)
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(value.len(), 100_000);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn changes_ship_between_stores() {
+        smol::block_on(async {
+            let value = |store: &Store, x: u32, to: u32| {
+                let store = store.namespace("a");
+                async move {
+                    store
+                        .entry(move |_: u32| Ok::<u32, StoreError>(to))
+                        .param(x)
+                        .refresh()
+                        .await
+                        .unwrap()
+                }
+            };
+            let cached = |store: &Store, x: u32| {
+                let store = store.namespace("a");
+                async move {
+                    store
+                        .entry(|_: u32| -> Result<u32, StoreError> { panic!("not cached") })
+                        .param(x)
+                        .run()
+                        .await
+                        .unwrap()
+                }
+            };
+            let a = Store::in_memory().await.unwrap();
+            value(&a, 1, 10).await;
+            value(&a, 2, 20).await;
+            let mut delta = Vec::new();
+            let seq = a.changes_since(0, &mut delta).await.unwrap();
+            assert_eq!(
+                delta
+                    .split(|&b| b == b'\n')
+                    .filter(|l| !l.is_empty())
+                    .count(),
+                2
+            );
+
+            let b = Store::in_memory().await.unwrap();
+            let report = b
+                .import(delta.as_slice(), ConflictPolicy::LastWriterWins)
+                .await;
+            assert_eq!(report.unwrap().imported, 2);
+
+            // Hits are not changes.
+            cached(&a, 1).await;
+            let mut delta = Vec::new();
+            assert_eq!(a.changes_since(seq, &mut delta).await.unwrap(), seq);
+            assert!(delta.is_empty());
+
+            value(&a, 1, 11).await;
+            a.inner.delete_value("a,2").await.unwrap();
+            let mut delta = Vec::new();
+            let next = a.changes_since(seq, &mut delta).await.unwrap();
+            assert!(next > seq);
+            let text = String::from_utf8(delta.clone()).unwrap();
+            assert!(text.contains(r#"{"key":"a,2","deleted_at":"#), "{text}");
+            let report = b
+                .import(delta.as_slice(), ConflictPolicy::LastWriterWins)
+                .await
+                .unwrap();
+            assert_eq!(
+                (report.imported, report.deleted, report.overwritten),
+                (1, 1, 2)
+            );
+            assert_eq!(cached(&b, 1).await, 11);
+            assert_eq!(b.entry_info("a,2").await.unwrap(), None);
+
+            // `c` writes after `a`, so its entry wins either way round.
+            smol::Timer::after(std::time::Duration::from_millis(5)).await;
+            let c = Store::in_memory().await.unwrap();
+            value(&c, 1, 99).await;
+            let report = c.merge_from(&a, ConflictPolicy::LastWriterWins).await;
+            assert_eq!(report.unwrap().skipped, 1);
+            assert_eq!(cached(&c, 1).await, 99);
+            let report = a.merge_from(&c, ConflictPolicy::LastWriterWins).await;
+            assert_eq!(report.unwrap().overwritten, 1);
+            assert_eq!(cached(&a, 1).await, 99);
+
+            // Keep-remote and keep-local.
+            value(&b, 1, 12).await;
+            c.merge_from(&b, ConflictPolicy::SkipExisting)
+                .await
+                .unwrap();
+            assert_eq!(cached(&c, 1).await, 99);
+            c.merge_from(&b, ConflictPolicy::Overwrite).await.unwrap();
+            assert_eq!(cached(&c, 1).await, 12);
+
+            // Views of one store share its entries, so there's nothing to
+            // copy, but it mustn't wait on itself either.
+            let report = c
+                .namespace("b")
+                .merge_from(&c.namespace("a"), ConflictPolicy::SkipExisting)
+                .await
+                .unwrap();
+            assert_eq!((report.imported, report.skipped), (0, 1));
+        });
+    }
+
+    #[test]
+    fn memory_cache_serves_hot_keys() {
+        smol::block_on(async {
+            let store = Store::open_with(":memory:", StoreOptions::new().memory_cache(2))
+                .await
+                .unwrap();
+            let square = |x: u32| {
+                let store = &store;
+                async move {
+                    store
+                        .namespace("square")
+                        .entry(|x: u32| Ok::<u32, StoreError>(x * x))
+                        .param(x)
+                        .run()
+                        .await
+                        .unwrap()
+                }
+            };
+            // Rewrite the rows behind the store's back: only a lookup that
+            // reaches the database sees it.
+            let tamper = || async {
+                store
+                    .inner
+                    .write(|conn| {
+                        conn.connection()
+                            .execute("UPDATE potency SET value = '0'")
+                            .map_err(StoreError::from)
+                    })
+                    .await
+                    .unwrap()
+            };
+
+            assert_eq!(square(3).await, 9);
+            tamper().await;
+            assert_eq!(square(3).await, 9);
+
+            // Least recently used keys are evicted.
+            assert_eq!(square(4).await, 16);
+            assert_eq!(square(5).await, 25);
+            assert_eq!(square(3).await, 0);
+
+            // Reads fill the memory tier too, and deletions empty it.
+            tamper().await;
+            assert_eq!(square(3).await, 0);
+            store.inner.delete_value("square,3").await.unwrap();
+            assert_eq!(square(3).await, 9);
+
+            // So do imports.
+            let mut exported = vec![];
+            store.export(&mut exported).await.unwrap();
+            tamper().await;
+            assert_eq!(square(5).await, 25);
+            store
+                .import(exported.as_slice(), ConflictPolicy::Overwrite)
+                .await
+                .unwrap();
+            assert_eq!(square(5).await, 0);
+
+            // Hits in memory still count.
+            assert_eq!(square(3).await, 9);
+            assert_eq!(square(3).await, 9);
+            let info = store.entry_info("square,3").await.unwrap().unwrap();
+            assert_eq!(info.hit_count, 2);
+        });
+    }
+
+    #[test]
+    fn gc_deletes_outside_roots() {
+        smol::block_on(async {
+            let options = StoreOptions::new().blobs_above(64);
+            let store = Store::open_with(":memory:", options).await.unwrap();
+            let put = |key: &'static str, len: usize| {
+                let store = store.clone();
+                async move {
+                    let mut view = store.clone();
+                    for segment in key.split(',') {
+                        view = view.namespace(segment);
+                    }
+                    view.entry(move || Ok::<String, StoreError>("x".repeat(len)))
+                        .run()
+                        .await
+                        .unwrap();
+                }
+            };
+            put("squares,1", 1).await;
+            put("squares,12", 100).await;
+            put("cubes,2", 1).await;
+            put("old,1", 100).await;
+            let _ = store
+                .namespace("old")
+                .entry(|| -> Result<u32, StoreError> { panic!("gone") })
+                .catch_panics()
+                .run()
+                .await;
+
+            // Prefixes match whole segments.
+            let roots = Roots::new().prefix("squares,1").prefix("cubes");
+            let report = store.gc_dry_run(&roots).await.unwrap();
+            assert_eq!(report.total.entries, 2);
+            assert_eq!(report.by_namespace["squares"].entries, 1);
+            assert_eq!(report.by_namespace["old"].entries, 1);
+            // The quotes around the JSON string count, and blobs are sized.
+            assert_eq!(report.total.bytes, 204);
+            assert_eq!(store.stats().await.unwrap().entries, 4);
+
+            // A view only collects its own namespace.
+            let report = store.namespace("old").gc(&Roots::new()).await.unwrap();
+            assert_eq!(report.total.entries, 1);
+            assert_eq!(store.panics("old").await.unwrap(), 0);
+            let report = store.gc(&roots).await.unwrap();
+            assert_eq!(report.total.entries, 1);
+            let stats = store.stats().await.unwrap();
+            assert_eq!((stats.entries, stats.blobs), (2, 0));
+        });
+    }
+
+    #[test]
+    fn tags_invalidate_across_namespaces() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            let calls = Counter::default();
+            let run = |ns: &'static str, pin: bool| {
+                let (store, calls) = (store.clone(), calls.clone());
+                async move {
+                    let view = store.namespace(ns);
+                    let mut builder = view
+                        .entry(move |x: u32| {
+                            calls.bump();
+                            Ok::<u32, StoreError>(x + 1)
+                        })
+                        .param(42u32)
+                        .tag("dataset-42")
+                        .tag("derived");
+                    if pin {
+                        builder = builder.pin();
+                    }
+                    builder.run().await.unwrap()
+                }
+            };
+            run("stats", false).await;
+            run("golden", true).await;
+            store
+                .namespace("other")
+                .entry(|| Ok::<u32, StoreError>(0))
+                .run()
+                .await
+                .unwrap();
+
+            let info = store.entry_info("golden,42").await.unwrap().unwrap();
+            assert!(info.pinned);
+            assert_eq!(info.tags, ["dataset-42", "derived"]);
+
+            // Pinned entries outlive gc.
+            let report = store.gc(&Roots::new()).await.unwrap();
+            assert_eq!(report.total.entries, 2);
+            assert!(store.entry_info("golden,42").await.unwrap().is_some());
+
+            // But not their tag's invalidation.
+            run("stats", false).await;
+            assert_eq!(calls.get(), 3);
+            assert_eq!(store.invalidate_tag("dataset-42").await.unwrap(), 2);
+            assert_eq!(store.invalidate_tag("derived").await.unwrap(), 0);
+            run("stats", false).await;
+            run("golden", true).await;
+            assert_eq!(calls.get(), 5);
+
+            // Tags travel with exports.
+            let mut exported = vec![];
+            store.export(&mut exported).await.unwrap();
+            let copy = Store::in_memory().await.unwrap();
+            copy.import(exported.as_slice(), ConflictPolicy::Fail)
+                .await
+                .unwrap();
+            assert_eq!(copy.invalidate_tag("derived").await.unwrap(), 2);
+        });
+    }
+
+    /// Nested durable calls are recorded as edges from the outer key to the
+    /// inner ones, replaced when the outer entry is recomputed.
+    #[test]
+    fn nested_calls_record_dependencies() {
+        smol::block_on(async {
+            let store = Store::in_memory().await.unwrap();
+            let other = Store::in_memory().await.unwrap();
+            let leaf = |store: Store, x: u32| async move {
+                store
+                    .namespace("leaf")
+                    .entry(|x: u32| Ok::<u32, StoreError>(x + 1))
+                    .param(x)
+                    .run()
+                    .await
+            };
+            let inner = {
+                let store = store.clone();
+                move |x: u32| {
+                    let store = store.clone();
+                    async move {
+                        let view = store.namespace("inner");
+                        let (store, leaf) = (store.clone(), leaf);
+                        view.entry_async(move |x: u32| leaf(store.clone(), x))
+                            .param(x)
+                            .run()
+                            .await
+                    }
+                }
+            };
+            let outer = |n: u32| {
+                let (store, other, inner) = (store.clone(), other.clone(), inner.clone());
+                move |x: u32| {
+                    let (store, other, inner) = (store.clone(), other.clone(), inner.clone());
+                    async move {
+                        let mut sum = leaf(other, x).await?;
+                        for i in 0..n {
+                            sum += inner(x + i).await?;
+                        }
+                        // Read twice, recorded once.
+                        sum += leaf(store.clone(), x).await? + leaf(store, x).await?;
+                        Ok::<u32, StoreError>(sum)
+                    }
+                }
+            };
+
+            store
+                .namespace("outer")
+                .entry_async(outer(2))
+                .param(1u32)
+                .tag("outer")
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(
+                store.dependencies("outer,1").await.unwrap(),
+                ["inner,1", "inner,2", "leaf,1"]
+            );
+            assert_eq!(store.dependencies("inner,2").await.unwrap(), ["leaf,2"]);
+            assert_eq!(
+                store.dependents("leaf,1").await.unwrap(),
+                ["inner,1", "outer,1"]
+            );
+            // Calls into another store aren't recorded in either.
+            assert!(other.dependents("leaf,1").await.unwrap().is_empty());
+
+            // Cache hits count, and a recomputation replaces the edges.
+            store
+                .namespace("outer")
+                .entry_async(outer(1))
+                .param(1u32)
+                .tag("outer")
+                .refresh()
+                .await
+                .unwrap();
+            assert_eq!(
+                store.dependencies("outer,1").await.unwrap(),
+                ["inner,1", "leaf,1"]
+            );
+            assert!(store.dependents("inner,2").await.unwrap().is_empty());
+
+            // Deleting an entry deletes its edges.
+            store.invalidate_tag("outer").await.unwrap();
+            assert!(store.dependencies("outer,1").await.unwrap().is_empty());
+            assert_eq!(store.dependents("leaf,1").await.unwrap(), ["inner,1"]);
+        });
+    }
 }
 // (debug tests removed)
@@ -0,0 +1,173 @@
+//! The in-process tier in front of the database; see
+//! [`StoreOptions::memory_cache`][crate::StoreOptions::memory_cache].
+//!
+//! Values are kept decoded, one per key and output type, so a hit neither
+//! queries SQLite nor runs the codec. Keys are evicted least recently used
+//! first.
+
+use std::{
+    any::{Any, TypeId},
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+pub(crate) struct Memory {
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+#[derive(Default)]
+struct Lru {
+    slots: HashMap<String, Slot>,
+    /// Keys by when they were last used, oldest first.
+    order: BTreeMap<u64, String>,
+    clock: u64,
+    /// Bumped whenever a value is replaced or dropped, so that a value read
+    /// from the database before then isn't put back over a newer one.
+    epoch: u64,
+}
+
+/// The values cached under one key.
+struct Slot {
+    used: u64,
+    /// When the row they were read from expires, in Unix milliseconds.
+    expires_at: Option<i64>,
+    /// The row decoded as each type it was read as.
+    values: Vec<(TypeId, Box<dyn Any + Send>)>,
+}
+
+impl Memory {
+    /// A cache of at most `capacity` keys.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Memory {
+            capacity,
+            lru: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        // Critical sections don't call user code, so a poisoned lock only
+        // means a panic elsewhere; the cache itself is consistent.
+        self.lru.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    /// The value under `key` as an `O`, unless it is missing or expired at
+    /// `now`.
+    pub(crate) fn get<O: Clone + 'static>(&self, key: &str, now: i64) -> Option<O> {
+        let mut lru = self.lock();
+        let lru = &mut *lru;
+        let slot = lru.slots.get_mut(key)?;
+        if slot.expires_at.is_some_and(|at| now >= at) {
+            return None;
+        }
+        let value = slot
+            .values
+            .iter()
+            .find(|(type_id, _)| *type_id == TypeId::of::<O>())?
+            .1
+            .downcast_ref::<O>()?
+            .clone();
+        lru.clock += 1;
+        let key = lru
+            .order
+            .remove(&slot.used)
+            .unwrap_or_else(|| key.to_owned());
+        slot.used = lru.clock;
+        lru.order.insert(lru.clock, key);
+        Some(value)
+    }
+
+    /// The current epoch, to pass to [`Memory::insert`] for a value about
+    /// to be read from the database.
+    pub(crate) fn epoch(&self) -> u64 {
+        self.lock().epoch
+    }
+
+    /// Cache `value`, read from the row under `key` while the cache was at
+    /// `epoch`, next to the key's values of other types. Does nothing if a
+    /// value was replaced or dropped since.
+    pub(crate) fn insert<O: Send + 'static>(
+        &self,
+        key: &str,
+        epoch: u64,
+        value: O,
+        expires_at: Option<i64>,
+    ) {
+        let mut lru = self.lock();
+        if lru.epoch != epoch {
+            return;
+        }
+        self.put(&mut lru, key, value, expires_at, false);
+    }
+
+    /// Cache `value`, just written to the row under `key`, dropping the
+    /// key's values of other types.
+    pub(crate) fn replace<O: Send + 'static>(&self, key: &str, value: O, expires_at: Option<i64>) {
+        let mut lru = self.lock();
+        lru.epoch += 1;
+        self.put(&mut lru, key, value, expires_at, true);
+    }
+
+    fn put<O: Send + 'static>(
+        &self,
+        lru: &mut Lru,
+        key: &str,
+        value: O,
+        expires_at: Option<i64>,
+        replace: bool,
+    ) {
+        lru.clock += 1;
+        let used = lru.clock;
+        let value: (TypeId, Box<dyn Any + Send>) = (TypeId::of::<O>(), Box::new(value));
+        match lru.slots.get_mut(key) {
+            Some(slot) => {
+                let key = lru
+                    .order
+                    .remove(&slot.used)
+                    .unwrap_or_else(|| key.to_owned());
+                lru.order.insert(used, key);
+                slot.used = used;
+                if replace || slot.expires_at != expires_at {
+                    slot.values.clear();
+                    slot.expires_at = expires_at;
+                }
+                slot.values.retain(|(type_id, _)| *type_id != value.0);
+                slot.values.push(value);
+            }
+            None => {
+                lru.order.insert(used, key.to_owned());
+                lru.slots.insert(
+                    key.to_owned(),
+                    Slot {
+                        used,
+                        expires_at,
+                        values: vec![value],
+                    },
+                );
+                while lru.slots.len() > self.capacity {
+                    let Some((_, oldest)) = lru.order.pop_first() else {
+                        break;
+                    };
+                    lru.slots.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    /// Drop the values under `key`.
+    pub(crate) fn remove(&self, key: &str) {
+        let mut lru = self.lock();
+        lru.epoch += 1;
+        if let Some(slot) = lru.slots.remove(key) {
+            lru.order.remove(&slot.used);
+        }
+    }
+
+    /// Drop every value.
+    pub(crate) fn clear(&self) {
+        let mut lru = self.lock();
+        lru.epoch += 1;
+        lru.slots.clear();
+        lru.order.clear();
+    }
+}
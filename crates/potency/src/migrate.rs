@@ -0,0 +1,371 @@
+//! Versioned schema migrations for the store database.
+//!
+//! The schema version lives in the `{table}_meta` table (`potency_meta` for
+//! the default table name). [`MIGRATIONS`] is applied in order, each step
+//! bumping the version by one, inside a single transaction. Steps must be
+//! safe to run against databases written before versioning existed, which
+//! may already contain some of what the step creates.
+
+use crate::{db::Tables, SchemaNeedsMigrationSnafu, SchemaTooNewSnafu, StoreError};
+
+type Migration = fn(&sqlite::Connection, &Tables) -> Result<(), StoreError>;
+
+/// Every migration, oldest first. Migration `i` upgrades version `i` to
+/// `i + 1`. Only ever append to this list.
+const MIGRATIONS: &[Migration] = &[
+    // 1: the values table, as created by the original `Store::open`.
+    |connection, tables| {
+        connection.execute(format!(
+            r#"CREATE TABLE IF NOT EXISTS "{}"(
+                key TEXT PRIMARY KEY NOT NULL,
+                value TEXT NOT NULL
+            )"#,
+            tables.values
+        ))?;
+        Ok(())
+    },
+    // 2: creation and expiry timestamps for TTLs.
+    |connection, tables| {
+        add_column_if_missing(
+            connection,
+            &tables.values,
+            "created_at",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        add_column_if_missing(connection, &tables.values, "expires_at", "INTEGER")
+    },
+    // 3: recorded panics.
+    |connection, tables| {
+        connection.execute(format!(
+            r#"CREATE TABLE IF NOT EXISTS "{}"(
+                key TEXT PRIMARY KEY NOT NULL,
+                panics INTEGER NOT NULL,
+                message TEXT NOT NULL
+            )"#,
+            tables.failures
+        ))?;
+        Ok(())
+    },
+    // 4: per-row codecs. SQLite keeps BLOBs as they are whatever the
+    // column's declared type, so `value` itself is left alone; existing
+    // rows are JSON text.
+    |connection, tables| {
+        add_column_if_missing(
+            connection,
+            &tables.values,
+            "codec",
+            "TEXT NOT NULL DEFAULT 'json'",
+        )
+    },
+    // 5: compression, and the encoded size before it for `Store::stats`.
+    // Both stay NULL for older rows, which are uncompressed.
+    |connection, tables| {
+        add_column_if_missing(connection, &tables.values, "compression", "TEXT")?;
+        add_column_if_missing(connection, &tables.values, "raw_size", "INTEGER")
+    },
+    // 6: content-addressed blobs. A row whose `blob` is set keeps its value
+    // in the blobs table instead.
+    |connection, tables| {
+        connection.execute(format!(
+            r#"CREATE TABLE IF NOT EXISTS "{}"(
+                hash TEXT PRIMARY KEY NOT NULL,
+                value BLOB NOT NULL,
+                compression TEXT
+            )"#,
+            tables.blobs
+        ))?;
+        add_column_if_missing(connection, &tables.values, "blob", "TEXT")?;
+        connection.execute(format!(
+            r#"CREATE INDEX IF NOT EXISTS "{0}_blob" ON "{0}"(blob)"#,
+            tables.values
+        ))?;
+        Ok(())
+    },
+    // 7: the id of the key a value is encrypted with, NULL if it isn't.
+    |connection, tables| {
+        add_column_if_missing(connection, &tables.values, "key_id", "TEXT")?;
+        add_column_if_missing(connection, &tables.blobs, "key_id", "TEXT")
+    },
+    // 8: per-entry metadata for `Store::entry_info`. `compute_duration` is
+    // in microseconds.
+    |connection, tables| {
+        let table = &tables.values;
+        add_column_if_missing(connection, table, "last_accessed_at", "INTEGER")?;
+        add_column_if_missing(connection, table, "hit_count", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(connection, table, "compute_duration", "INTEGER")?;
+        add_column_if_missing(connection, table, "potency_version", "TEXT")
+    },
+    // 9: the changelog for `Store::changes_since`, one row per key at the
+    // sequence number of its latest change, kept by triggers. Hits don't
+    // count as changes. Existing keys are logged as changed.
+    |connection, tables| {
+        connection.execute(format!(
+            r#"CREATE TABLE IF NOT EXISTS "{changes}"(
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                key TEXT NOT NULL UNIQUE,
+                deleted_at INTEGER
+            );
+            INSERT OR IGNORE INTO "{changes}" (key) SELECT key FROM "{values}" ORDER BY key;
+            CREATE TRIGGER IF NOT EXISTS "{values}_log_insert" AFTER INSERT ON "{values}"
+            BEGIN
+                DELETE FROM "{changes}" WHERE key = NEW.key;
+                INSERT INTO "{changes}" (key) VALUES (NEW.key);
+            END;
+            CREATE TRIGGER IF NOT EXISTS "{values}_log_update"
+            AFTER UPDATE OF key, value, codec, blob, created_at, expires_at ON "{values}"
+            BEGIN
+                DELETE FROM "{changes}" WHERE key IN (OLD.key, NEW.key);
+                INSERT INTO "{changes}" (key) VALUES (NEW.key);
+            END;
+            CREATE TRIGGER IF NOT EXISTS "{values}_log_delete" AFTER DELETE ON "{values}"
+            BEGIN
+                DELETE FROM "{changes}" WHERE key = OLD.key;
+                INSERT INTO "{changes}" (key, deleted_at)
+                VALUES (OLD.key, CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER));
+            END"#,
+            changes = tables.changes,
+            values = tables.values,
+        ))?;
+        Ok(())
+    },
+    // 10: pins and tags, from `Builder::pin` and `Builder::tag`. A row's
+    // tags are deleted with it.
+    |connection, tables| {
+        add_column_if_missing(
+            connection,
+            &tables.values,
+            "pinned",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        connection.execute(format!(
+            r#"CREATE TABLE IF NOT EXISTS "{tags}"(
+                key TEXT NOT NULL,
+                tag TEXT NOT NULL,
+                PRIMARY KEY (key, tag)
+            ) WITHOUT ROWID;
+            CREATE INDEX IF NOT EXISTS "{tags}_tag" ON "{tags}"(tag);
+            CREATE TRIGGER IF NOT EXISTS "{values}_untag" AFTER DELETE ON "{values}"
+            BEGIN
+                DELETE FROM "{tags}" WHERE key = OLD.key;
+            END"#,
+            tags = tables.tags,
+            values = tables.values,
+        ))?;
+        Ok(())
+    },
+    // 11: dependencies between entries, recorded when one is computed
+    // while running another. A row's edges to what it read are deleted
+    // with it.
+    |connection, tables| {
+        connection.execute(format!(
+            r#"CREATE TABLE IF NOT EXISTS "{deps}"(
+                parent TEXT NOT NULL,
+                child TEXT NOT NULL,
+                PRIMARY KEY (parent, child)
+            ) WITHOUT ROWID;
+            CREATE INDEX IF NOT EXISTS "{deps}_child" ON "{deps}"(child);
+            CREATE TRIGGER IF NOT EXISTS "{values}_undepend" AFTER DELETE ON "{values}"
+            BEGIN
+                DELETE FROM "{deps}" WHERE parent = OLD.key;
+            END"#,
+            deps = tables.deps,
+            values = tables.values,
+        ))?;
+        Ok(())
+    },
+];
+
+/// The schema version this build of `potency` reads and writes.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Bring the database up to [`SCHEMA_VERSION`].
+///
+/// Fails with [`StoreError::SchemaTooNew`] if the database was written by a
+/// newer `potency`, leaving it untouched.
+pub(crate) fn migrate(connection: &sqlite::Connection, tables: &Tables) -> Result<(), StoreError> {
+    connection.execute(format!(
+        r#"CREATE TABLE IF NOT EXISTS "{}"(
+            key TEXT PRIMARY KEY NOT NULL,
+            value INTEGER NOT NULL
+        )"#,
+        tables.meta
+    ))?;
+    // Check before taking the write lock so an up-to-date store opens
+    // without contending with other processes.
+    if check(connection, tables)? == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    connection.execute("BEGIN IMMEDIATE")?;
+    let result = (|| {
+        // Re-read under the lock: another process may have migrated.
+        let found = check(connection, tables)?;
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+            log::debug!(
+                "migrating {} to schema version {}",
+                tables.values,
+                version + 1
+            );
+            migration(connection, tables)?;
+        }
+        let mut statement = connection.prepare(format!(
+            r#"INSERT OR REPLACE INTO "{}" (key, value) VALUES ('schema_version', :version)"#,
+            tables.meta
+        ))?;
+        statement.bind((":version", SCHEMA_VERSION as i64))?;
+        let _ = statement.next()?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => connection.execute("COMMIT")?,
+        Err(e) => {
+            let _ = connection.execute("ROLLBACK");
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Check that a database opened read-only is at [`SCHEMA_VERSION`], since
+/// it can't be migrated.
+pub(crate) fn ensure_current(
+    connection: &sqlite::Connection,
+    tables: &Tables,
+) -> Result<(), StoreError> {
+    let found = check(connection, tables)?;
+    if found < SCHEMA_VERSION {
+        return SchemaNeedsMigrationSnafu {
+            found,
+            supported: SCHEMA_VERSION,
+        }
+        .fail();
+    }
+    Ok(())
+}
+
+/// The database's schema version, failing if it is newer than ours.
+fn check(connection: &sqlite::Connection, tables: &Tables) -> Result<u32, StoreError> {
+    let found = schema_version(connection, tables)?;
+    if found > SCHEMA_VERSION {
+        return SchemaTooNewSnafu {
+            found,
+            supported: SCHEMA_VERSION,
+        }
+        .fail();
+    }
+    Ok(found)
+}
+
+/// The recorded schema version, or 0 for a new or pre-versioning database.
+fn schema_version(connection: &sqlite::Connection, tables: &Tables) -> Result<u32, StoreError> {
+    let mut statement = connection
+        .prepare("SELECT count(*) AS n FROM sqlite_master WHERE type = 'table' AND name = :name")?;
+    statement.bind((":name", tables.meta.as_str()))?;
+    statement.next()?;
+    if statement.read::<i64, _>("n")? == 0 {
+        return Ok(0);
+    }
+    let mut statement = connection.prepare(format!(
+        r#"SELECT value FROM "{}" WHERE key = 'schema_version'"#,
+        tables.meta
+    ))?;
+    match statement.next()? {
+        sqlite::State::Row => Ok(statement.read::<i64, _>("value")? as u32),
+        sqlite::State::Done => Ok(0),
+    }
+}
+
+/// `ALTER TABLE ... ADD COLUMN` unless `table` already has `column`.
+fn add_column_if_missing(
+    connection: &sqlite::Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<(), StoreError> {
+    let mut statement = connection.prepare(format!(r#"PRAGMA table_info("{table}")"#))?;
+    while let sqlite::State::Row = statement.next()? {
+        if statement.read::<String, _>("name")? == column {
+            return Ok(());
+        }
+    }
+    connection.execute(format!(
+        r#"ALTER TABLE "{table}" ADD COLUMN {column} {decl}"#
+    ))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Store, StoreError, StoreOptions};
+
+    use super::*;
+
+    fn temp_db(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("potency-migrate-test-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("state.db")
+    }
+
+    #[test]
+    fn upgrades_pre_versioning_database_in_place() {
+        let db = temp_db("legacy");
+        {
+            // What the original `Store::open` left behind.
+            let connection = sqlite::Connection::open(&db).unwrap();
+            connection
+                .execute(
+                    "CREATE TABLE potency(key TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL);
+                     INSERT INTO potency (key, value) VALUES ('1', '2');",
+                )
+                .unwrap();
+        }
+        smol::block_on(async {
+            let store = Store::open(&db).await.unwrap();
+            let n = store
+                .entry(|_: u32| -> Result<u32, StoreError> { panic!("should be a hit") })
+                .param(1u32)
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(n, 2);
+            store.close().await.unwrap();
+        });
+        let connection = sqlite::Connection::open(&db).unwrap();
+        let tables = Tables::new("potency").unwrap();
+        assert_eq!(
+            schema_version(&connection, &tables).unwrap(),
+            SCHEMA_VERSION
+        );
+        // Idempotent on reopen.
+        migrate(&connection, &tables).unwrap();
+        let _ = std::fs::remove_dir_all(db.parent().unwrap());
+    }
+
+    #[test]
+    fn newer_schema_is_rejected() {
+        let db = temp_db("newer");
+        smol::block_on(async { Store::open(&db).await.unwrap().close().await }).unwrap();
+        {
+            let connection = sqlite::Connection::open(&db).unwrap();
+            connection
+                .execute("UPDATE potency_meta SET value = 9999 WHERE key = 'schema_version'")
+                .unwrap();
+        }
+        let result = smol::block_on(Store::open(&db));
+        assert!(
+            matches!(
+                result,
+                Err(StoreError::SchemaTooNew {
+                    found: 9999,
+                    supported: SCHEMA_VERSION
+                })
+            ),
+            "unexpected {:?}",
+            result.err()
+        );
+        let result = smol::block_on(Store::open_with(&db, StoreOptions::new().read_only(true)));
+        assert!(matches!(result, Err(StoreError::SchemaTooNew { .. })));
+        let _ = std::fs::remove_dir_all(db.parent().unwrap());
+    }
+}
@@ -0,0 +1,63 @@
+//! Limits shared by the TCP servers and clients of [`sync`][crate::sync]
+//! and [`remote`][crate::remote].
+
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+/// How long a request may wait on the network before it fails, and how
+/// long a server waits on an idle connection before closing it.
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long connecting to a server may take.
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many connections a server serves at once. Others wait to be
+/// accepted.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Connect to the first of `addrs` that answers within
+/// [`CONNECT_TIMEOUT`].
+pub(crate) fn connect(addrs: impl ToSocketAddrs) -> std::io::Result<TcpStream> {
+    let mut failure = None;
+    for addr in addrs.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => failure = Some(e),
+        }
+    }
+    Err(failure.unwrap_or_else(|| std::io::Error::other("no address to connect to")))
+}
+
+/// The connections a server is serving.
+#[derive(Default)]
+pub(crate) struct Slots {
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// One of the [`Slots`], freed when dropped.
+pub(crate) struct Slot(Arc<Slots>);
+
+impl Slots {
+    /// Wait until fewer than [`MAX_CONNECTIONS`] are served, and take a
+    /// slot.
+    pub(crate) fn acquire(slots: &Arc<Slots>) -> Slot {
+        // Critical sections don't panic, so a poisoned lock is consistent.
+        let mut used = slots.used.lock().unwrap_or_else(|p| p.into_inner());
+        while *used >= MAX_CONNECTIONS {
+            used = slots.freed.wait(used).unwrap_or_else(|p| p.into_inner());
+        }
+        *used += 1;
+        Slot(slots.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.used.lock().unwrap_or_else(|p| p.into_inner()) -= 1;
+        self.0.freed.notify_one();
+    }
+}
@@ -0,0 +1,307 @@
+//! Options for opening a [`Store`][crate::Store].
+
+use std::sync::Arc;
+
+use crate::{db::Keyring, Codec, Compression};
+
+/// How a [`Store`][crate::Store] uses its cache.
+///
+/// Set for the whole store with [`StoreOptions::mode`], or per view with
+/// [`Store::with_mode`][crate::Store::with_mode].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Serve hits, compute and store misses. The default.
+    #[default]
+    ReadWrite,
+    /// Serve hits and compute misses, but never write to the database.
+    /// Useful for replaying against a checked-in store in CI.
+    ReadOnly,
+    /// Serve hits; a miss is an error instead of a computation. Useful for
+    /// offline runs that must not reach the network.
+    CacheOnly,
+    /// Ignore the cache entirely: always compute, never read or write.
+    /// Useful for debugging.
+    Bypass,
+}
+
+impl Mode {
+    /// Whether lookups consult the database.
+    pub(crate) fn reads(self) -> bool {
+        !matches!(self, Mode::Bypass)
+    }
+
+    /// Whether results and bookkeeping are written to the database.
+    pub(crate) fn writes(self) -> bool {
+        matches!(self, Mode::ReadWrite)
+    }
+
+    /// Whether a miss may run the user's function.
+    pub(crate) fn computes(self) -> bool {
+        !matches!(self, Mode::CacheOnly)
+    }
+}
+
+/// SQLite's `journal_mode`. See <https://sqlite.org/pragma.html#pragma_journal_mode>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    /// Write-ahead logging: readers don't block the writer and vice versa.
+    /// The usual choice when several processes share a store file.
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    pub(crate) fn as_sql(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+/// SQLite's `synchronous` level. See <https://sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    /// Safe from corruption in WAL mode, but a power loss may roll back the
+    /// most recent writes.
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    pub(crate) fn as_sql(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Options for [`Store::open_with`][crate::Store::open_with].
+///
+/// Anything left unset keeps SQLite's own default.
+///
+/// ```rust,no_run
+/// # async fn doc() -> Result<(), potency::StoreError> {
+/// use std::time::Duration;
+/// use potency::{JournalMode, Store, StoreOptions, Synchronous};
+///
+/// let options = StoreOptions::new()
+///     .journal_mode(JournalMode::Wal)
+///     .synchronous(Synchronous::Normal)
+///     .busy_timeout(Duration::from_secs(5));
+/// let store = Store::open_with("state.db", options).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct StoreOptions {
+    pub(crate) mode: Mode,
+    pub(crate) journal_mode: Option<JournalMode>,
+    pub(crate) busy_timeout: Option<std::time::Duration>,
+    pub(crate) synchronous: Option<Synchronous>,
+    pub(crate) table_name: String,
+    pub(crate) read_only: bool,
+    pub(crate) readers: usize,
+    pub(crate) write_behind: Option<std::time::Duration>,
+    pub(crate) codec: Codec,
+    pub(crate) compression: Compression,
+    pub(crate) compress_above: usize,
+    pub(crate) blobs_above: Option<usize>,
+    pub(crate) encryption: Option<Arc<Keyring>>,
+    pub(crate) memory_cache: usize,
+    pub(crate) remote: Option<String>,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            journal_mode: None,
+            busy_timeout: None,
+            synchronous: None,
+            table_name: "potency".to_owned(),
+            read_only: false,
+            readers: 0,
+            write_behind: None,
+            codec: Codec::default(),
+            compression: Compression::default(),
+            compress_above: 16 * 1024,
+            blobs_above: None,
+            encryption: None,
+            memory_cache: 0,
+            remote: None,
+        }
+    }
+}
+
+impl StoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The store's [`Mode`]. Defaults to [`Mode::ReadWrite`].
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Set `PRAGMA journal_mode`. Ignored for read-only opens, since
+    /// changing the journal mode needs write access.
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> Self {
+        self.journal_mode = Some(journal_mode);
+        self
+    }
+
+    /// How long a connection waits on a lock held by another connection or
+    /// process before failing with `SQLITE_BUSY`.
+    pub fn busy_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    /// Set `PRAGMA synchronous`.
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = Some(synchronous);
+        self
+    }
+
+    /// Name of the table holding cached values. Defaults to `"potency"`.
+    ///
+    /// Bookkeeping tables are named after it, e.g. `"{name}_failures"`, so
+    /// several stores can share one database file. Must be a plain SQL
+    /// identifier (ASCII letters, digits and `_`).
+    pub fn table_name(mut self, name: impl Into<String>) -> Self {
+        self.table_name = name.into();
+        self
+    }
+
+    /// Serve lookups from a pool of `readers` read-only connections, next to
+    /// the single writer. Defaults to 0: everything shares the writer.
+    ///
+    /// With readers, a cache hit never waits on a write in progress. Unless
+    /// [`StoreOptions::journal_mode`] says otherwise this switches the
+    /// database to [`JournalMode::Wal`], which is what lets readers and the
+    /// writer proceed concurrently. Ignored for `":memory:"` stores.
+    pub fn readers(mut self, readers: usize) -> Self {
+        self.readers = readers;
+        self
+    }
+
+    /// Group writes into transactions, each committed at most
+    /// `flush_interval` after it began, instead of committing (and syncing)
+    /// every write on its own.
+    ///
+    /// **Durability.** A write counts as done once it is in the open
+    /// transaction, before it reaches disk. If the process exits without
+    /// dropping the store or calling [`Store::flush`][crate::Store::flush],
+    /// or a commit fails, up to `flush_interval` worth of results are lost
+    /// and will be recomputed next time. The store also holds SQLite's write
+    /// lock while a transaction is open, so other processes writing to the
+    /// same file wait up to `flush_interval`.
+    pub fn write_behind(mut self, flush_interval: std::time::Duration) -> Self {
+        self.write_behind = Some(flush_interval);
+        self
+    }
+
+    /// How new values are encoded. Defaults to [`Codec::Json`].
+    ///
+    /// Rows already in the store keep the codec they were written with and
+    /// stay readable.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// How large values are compressed. Defaults to [`Compression::None`].
+    /// Override per call with [`Builder::compression`][crate::Builder::compression].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Only compress values whose encoding is at least `bytes` long.
+    /// Defaults to 16 KiB.
+    pub fn compress_above(mut self, bytes: usize) -> Self {
+        self.compress_above = bytes;
+        self
+    }
+
+    /// Store values whose encoding is at least `bytes` long in a separate
+    /// content-addressed blobs table, keyed by their SHA-256, leaving only
+    /// the hash in the row. Off by default.
+    ///
+    /// Identical values under different keys share one blob, and the
+    /// values table stays small enough to scan quickly. Replaced and
+    /// deleted values leave their blobs behind until
+    /// [`Store::gc_blobs`][crate::Store::gc_blobs] runs.
+    pub fn blobs_above(mut self, bytes: usize) -> Self {
+        self.blobs_above = Some(bytes);
+        self
+    }
+
+    /// Encrypt values at rest with `keyring`. Off by default.
+    ///
+    /// Values are sealed with ChaCha20-Poly1305 after compression, and each
+    /// row records the id of the key it was sealed with. Cache keys are
+    /// only hidden if the keyring also [hashes them](Keyring::hash_keys).
+    /// Rows written before encryption was turned on stay readable; use
+    /// [`Store::reencrypt`][crate::Store::reencrypt] to seal them.
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, keyring: Keyring) -> Self {
+        self.encryption = Some(Arc::new(keyring));
+        self
+    }
+
+    /// Keep up to `entries` recently used values in memory, decoded, in
+    /// front of the database. Off by default.
+    ///
+    /// A hit on a value in memory skips both the database and the codec;
+    /// only the value's clone and its hit count remain. Values are written
+    /// through when they are stored, and dropped when their row is deleted
+    /// or rows are imported. Keys are evicted least recently used first,
+    /// and each key holds one value per type it was read as.
+    ///
+    /// Only writes made through this store are seen. If another process
+    /// writes to the same file, a value cached here is served until it
+    /// expires or is evicted.
+    pub fn memory_cache(mut self, entries: usize) -> Self {
+        self.memory_cache = entries;
+        self
+    }
+
+    /// Share results through the remote cache at `url`, an `http://` URL
+    /// such as `"http://cache.internal:7879/my-project"`. Off by default.
+    ///
+    /// A key that isn't in the store, or has expired, is looked up there
+    /// before it is computed, and a hit is stored locally. Computed values
+    /// are sent there without waiting; [`Store::flush`][crate::Store::flush]
+    /// waits until they are. If the remote can't be reached, lookups miss
+    /// and sends are dropped, with a warning logged, and the remote is left
+    /// alone for half a minute. With `StoreOptions::encryption`, values
+    /// are sealed before they are sent. See [`remote`][crate::remote] for
+    /// the protocol and a server.
+    pub fn remote(mut self, url: impl Into<String>) -> Self {
+        self.remote = Some(url.into());
+        self
+    }
+
+    /// Open the database read-only. Tables are not created, so the file must
+    /// already hold a store.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+}
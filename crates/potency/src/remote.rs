@@ -0,0 +1,870 @@
+//! A remote cache tier shared over HTTP.
+//!
+//! A store opened with [`StoreOptions::remote`][crate::StoreOptions::remote]
+//! looks up keys it doesn't have in a remote cache before computing them,
+//! and sends what it computes there, the way build systems share a remote
+//! cache between CI and developer machines. The local store stays the
+//! first tier: remote hits are written to it, and an unreachable remote
+//! only costs the lookup. Lookups give up after a few seconds and don't
+//! wait behind queued sends, and a remote that can't be reached is left
+//! alone for a while before it is tried again.
+//!
+//! [`Server`] is a reference implementation of the remote end, keeping
+//! entries as files in a directory; the `potency-remote` binary runs one.
+//!
+//! ```rust,no_run
+//! # async fn doc() -> Result<(), potency::StoreError> {
+//! use potency::{remote::Server, Store, StoreOptions};
+//!
+//! // On the cache host:
+//! let server = Server::bind("/var/cache/potency", "0.0.0.0:7879")?;
+//! std::thread::spawn(move || server.serve());
+//!
+//! // On each machine:
+//! let options = StoreOptions::new().remote("http://cache.internal:7879/my-project");
+//! let store = Store::open_with("state.db", options).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Protocol
+//!
+//! Plain HTTP/1.1, one entry per URL: the store's URL followed by `/` and
+//! the percent-encoded cache key (its keyed hash if the keyring hashes
+//! keys).
+//!
+//! - `GET` answers `200` with the entry, or `404` if there is none.
+//! - `PUT` stores the request body as the entry, answering `204`.
+//!
+//! Bodies are read as `Content-Length` bytes; the server writes a `PUT`
+//! body straight to disk rather than holding it in memory, and serves at
+//! most a fixed number of connections at once.
+//!
+//! An entry is one line of [`Store::export`][crate::Store::export]'s JSON
+//! Lines, so it carries its codec and expiry; the server never looks
+//! inside. A store with an encryption keyring seals each value with it,
+//! bound to the entry's key, so the server only ever holds ciphertext, and
+//! takes back only entries sealed that way. Keys and bookkeeping travel in
+//! the clear. There is no authentication or TLS: serve on a trusted
+//! network only, or behind a proxy that adds them.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use snafu::OptionExt;
+
+use crate::{
+    db::Keyring,
+    export::{Imported, Record},
+    net::{self, Slots, TIMEOUT},
+    RemoteSnafu, StoreError,
+};
+
+/// Bodies longer than this are refused.
+const MAX_BODY: u64 = 1 << 30;
+
+/// Heads longer than this are refused rather than buffered.
+const MAX_HEAD: u64 = 64 << 10;
+
+/// How long a lookup may wait on the network before it counts as a miss.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long the remote is left alone after it couldn't be reached.
+const COOL_DOWN: Duration = Duration::from_secs(30);
+
+/// Numbers the files [`Server`] writes entries to before renaming them.
+static PARTIAL: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Serves a remote cache from a directory.
+///
+/// Each entry is a file named after the SHA-256 of its URL path, so any
+/// number of stores can share a server, each under its own path.
+pub struct Server {
+    dir: PathBuf,
+    listener: TcpListener,
+}
+
+impl Server {
+    /// Listen on `addr`, keeping entries in `dir`, which is created if
+    /// missing. Bind to port 0 to have the system pick a free port, then
+    /// read it from [`Server::local_addr`].
+    pub fn bind(dir: impl Into<PathBuf>, addr: impl ToSocketAddrs) -> Result<Self, StoreError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Server {
+            dir,
+            listener: TcpListener::bind(addr)?,
+        })
+    }
+
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr, StoreError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections until the listener fails, each on its own
+    /// thread, up to a fixed number at once.
+    ///
+    /// Blocks the calling thread, so start it on one of its own.
+    pub fn serve(self) -> Result<(), StoreError> {
+        let dir = Arc::new(self.dir);
+        let slots = Arc::new(Slots::default());
+        loop {
+            let slot = Slots::acquire(&slots);
+            let (stream, _) = self.listener.accept()?;
+            let dir = dir.clone();
+            std::thread::Builder::new()
+                .name("potency-remote".into())
+                .spawn(move || {
+                    let _slot = slot;
+                    let peer = stream.peer_addr().ok();
+                    if let Err(e) = serve_connection(&dir, stream) {
+                        log::warn!("remote cache connection from {peer:?} failed: {e}");
+                    }
+                })?;
+        }
+    }
+}
+
+/// Answer requests on `stream` until the client closes it.
+fn serve_connection(dir: &std::path::Path, stream: TcpStream) -> Result<(), StoreError> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    while let Some(head) = read_head(&mut reader)? {
+        let len = content_length(&head)?;
+        let mut body = (&mut reader).take(len);
+        let mut parts = head.start.split(' ');
+        let (method, path) = (parts.next().unwrap_or_default(), parts.next());
+        let Some(path) = path.filter(|path| path.starts_with('/')) else {
+            std::io::copy(&mut body, &mut std::io::sink())?;
+            write_response(&mut writer, 400, "Bad Request", b"")?;
+            continue;
+        };
+        let file = {
+            use sha2::Digest;
+            dir.join(format!("{:x}", sha2::Sha256::digest(path.as_bytes())))
+        };
+        if method != "PUT" {
+            std::io::copy(&mut body, &mut std::io::sink())?;
+        }
+        match method {
+            "GET" => match std::fs::read(&file) {
+                Ok(entry) => write_response(&mut writer, 200, "OK", &entry)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    write_response(&mut writer, 404, "Not Found", b"")?
+                }
+                Err(e) => return Err(e.into()),
+            },
+            "PUT" => {
+                // Write next to the entry and rename, so a reader never
+                // sees half of it.
+                let n = PARTIAL.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let partial = file.with_extension(format!("partial-{n}"));
+                let written = std::fs::File::create(&partial)
+                    .and_then(|mut out| std::io::copy(&mut body, &mut out));
+                if written.as_ref().ok() != Some(&len) {
+                    let _ = std::fs::remove_file(&partial);
+                    written?;
+                    return RemoteSnafu {
+                        message: "connection closed in the middle of a body",
+                    }
+                    .fail();
+                }
+                std::fs::rename(&partial, &file)?;
+                write_response(&mut writer, 204, "No Content", b"")?;
+            }
+            _ => write_response(&mut writer, 405, "Method Not Allowed", b"")?,
+        }
+    }
+    Ok(())
+}
+
+/// The start line and headers of a request or response.
+struct Head {
+    start: String,
+    /// Lowercase names and their values.
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read a message head, or `None` if the peer closed the connection
+/// between messages.
+fn read_head(reader: &mut impl BufRead) -> Result<Option<Head>, StoreError> {
+    let mut reader = reader.take(MAX_HEAD);
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let start = line.trim_end().to_owned();
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            let message = match reader.limit() {
+                0 => format!("refusing a head over {MAX_HEAD} bytes"),
+                _ => "connection closed in the middle of a message".to_owned(),
+            };
+            return RemoteSnafu { message }.fail();
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').context(RemoteSnafu {
+            message: format!("malformed header {line:?}"),
+        })?;
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+    }
+    Ok(Some(Head { start, headers }))
+}
+
+/// The length of the body that follows `head`. Only `Content-Length`
+/// bodies are supported.
+fn content_length(head: &Head) -> Result<u64, StoreError> {
+    if head.header("transfer-encoding").is_some() {
+        return RemoteSnafu {
+            message: "chunked bodies are not supported",
+        }
+        .fail();
+    }
+    let len = match head.header("content-length") {
+        Some(len) => len.parse::<u64>().ok().context(RemoteSnafu {
+            message: format!("invalid Content-Length {len:?}"),
+        })?,
+        None => 0,
+    };
+    if len > MAX_BODY {
+        return RemoteSnafu {
+            message: format!("refusing a {len} byte body"),
+        }
+        .fail();
+    }
+    Ok(len)
+}
+
+/// Read the body that follows `head`.
+fn read_body(reader: &mut impl Read, head: &Head) -> Result<Vec<u8>, StoreError> {
+    let mut body = Vec::new();
+    reader.take(content_length(head)?).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+fn write_response(
+    writer: &mut impl Write,
+    status: u16,
+    reason: &str,
+    body: &[u8],
+) -> Result<(), StoreError> {
+    write!(
+        writer,
+        "HTTP/1.1 {status} {reason}\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// `key` with everything but unreserved URL characters percent-encoded.
+fn percent_encode(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// A unit of work for the remote tier's connection thread.
+type Job = Box<dyn FnOnce(&mut Http) + Send>;
+
+/// The client side of the remote tier, held by the store's database.
+///
+/// Lookups and writes each run on a thread of the tier's own over a
+/// kept-alive connection, so they never block the caller's executor and a
+/// lookup never waits behind queued writes. Writes are queued without
+/// waiting for them; [`Remote::flush`] waits.
+pub(crate) struct Remote {
+    lookups: mpsc::Sender<Job>,
+    writes: mpsc::Sender<Job>,
+    /// The URL's path, without a trailing `/`.
+    base: String,
+    keyring: Option<Arc<Keyring>>,
+    down: Arc<Down>,
+}
+
+/// Whether the remote couldn't be reached lately, shared by the tier's
+/// connections.
+#[derive(Default)]
+struct Down {
+    until: Mutex<Option<Instant>>,
+}
+
+impl Down {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        // Critical sections don't panic, so a poisoned lock is consistent.
+        self.until.lock().unwrap_or_else(|p| p.into_inner())
+    }
+
+    /// Whether the remote is being left alone.
+    fn is_down(&self) -> bool {
+        self.lock().is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Leave the remote alone for [`COOL_DOWN`].
+    fn mark(&self) {
+        *self.lock() = Some(Instant::now() + COOL_DOWN);
+    }
+}
+
+impl Remote {
+    /// Connect lazily to the remote cache at `url`, an `http://` URL
+    /// with an optional port and path.
+    pub(crate) fn new(url: &str, keyring: Option<Arc<Keyring>>) -> Result<Self, StoreError> {
+        let rest = url.strip_prefix("http://").context(RemoteSnafu {
+            message: format!("{url:?} is not an http:// URL"),
+        })?;
+        let (host, base) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return RemoteSnafu {
+                message: format!("{url:?} has no host"),
+            }
+            .fail();
+        }
+        let addr = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.to_owned(),
+            _ => format!("{host}:80"),
+        };
+        let down = Arc::new(Down::default());
+        let connection = |timeout: Duration| -> Result<mpsc::Sender<Job>, StoreError> {
+            let mut http = Http::new(&addr, host, timeout, down.clone());
+            let (jobs, queue) = mpsc::channel::<Job>();
+            std::thread::Builder::new()
+                .name("potency-remote-client".into())
+                .spawn(move || {
+                    for job in queue {
+                        job(&mut http);
+                    }
+                })?;
+            Ok(jobs)
+        };
+        Ok(Remote {
+            lookups: connection(LOOKUP_TIMEOUT)?,
+            writes: connection(TIMEOUT)?,
+            base: base.trim_end_matches('/').to_owned(),
+            keyring,
+            down,
+        })
+    }
+
+    /// The URL path of the entry under the row key `key`.
+    fn path(&self, key: &str) -> String {
+        format!("{}/{}", self.base, percent_encode(key))
+    }
+
+    /// Run `f` on the connection `jobs` is for, and wait for its result.
+    async fn submit<R: Send + 'static>(
+        jobs: &mpsc::Sender<Job>,
+        f: impl FnOnce(&mut Http) -> Result<R, StoreError> + Send + 'static,
+    ) -> Result<R, StoreError> {
+        let (reply, result) = async_channel::bounded(1);
+        jobs.send(Box::new(move |http| {
+            let _ = reply.send_blocking(f(http));
+        }))
+        .ok()
+        .context(RemoteSnafu {
+            message: "connection thread stopped",
+        })?;
+        result.recv().await.ok().context(RemoteSnafu {
+            message: "connection thread stopped",
+        })?
+    }
+
+    /// The entry for the cache key `key`, or `None` if the remote has
+    /// none or is being left alone.
+    pub(crate) async fn get(&self, key: &str) -> Result<Option<Imported>, StoreError> {
+        if self.down.is_down() {
+            log::trace!("{key:?} not looked up, the remote cache is unreachable");
+            return Ok(None);
+        }
+        let row_key = self
+            .keyring
+            .as_deref()
+            .and_then(|keyring| keyring.keyed_hash(key.as_bytes()))
+            .unwrap_or_else(|| key.to_owned());
+        let path = self.path(&row_key);
+        let (status, body) =
+            Self::submit(&self.lookups, move |http| http.request("GET", &path, b"")).await?;
+        match status {
+            200 => {
+                let record: Record = serde_json::from_slice(&body)?;
+                if record.key() != row_key {
+                    return RemoteSnafu {
+                        message: format!("asked for {row_key:?}, got {:?}", record.key()),
+                    }
+                    .fail();
+                }
+                // Only what was sealed with the keyring is trusted to be
+                // what a store using it sent.
+                if self.keyring.is_some() && !record.is_sealed() {
+                    return RemoteSnafu {
+                        message: format!("{row_key:?} is not sealed"),
+                    }
+                    .fail();
+                }
+                let record = record.open(self.keyring.as_deref())?;
+                Ok(Some(record.into_imported(None)?))
+            }
+            404 => Ok(None),
+            status => RemoteSnafu {
+                message: format!("GET answered {status}"),
+            }
+            .fail(),
+        }
+    }
+
+    /// Queue `record` to be sent to the remote, sealed if the store has a
+    /// keyring, without waiting. Dropped if the remote is being left alone.
+    pub(crate) fn put(&self, record: Record) {
+        if self.down.is_down() {
+            log::debug!(
+                "{:?} not sent, the remote cache is unreachable",
+                record.key()
+            );
+            return;
+        }
+        let path = self.path(record.key());
+        let sealed = match &self.keyring {
+            Some(keyring) => record.seal(keyring),
+            None => Ok(record),
+        };
+        let body = match sealed.and_then(|record| Ok(serde_json::to_vec(&record)?)) {
+            Ok(body) => body,
+            Err(e) => {
+                log::warn!("could not send {path} to the remote cache: {e}");
+                return;
+            }
+        };
+        let job: Job = Box::new(move |http| {
+            // Queued writes aren't each left to time out once one has.
+            if http.down.is_down() {
+                return;
+            }
+            match http.request("PUT", &path, &body) {
+                Ok((200..=299, _)) => {}
+                Ok((status, _)) => log::warn!("remote cache answered {status} to PUT {path}"),
+                Err(e) => log::warn!("could not PUT {path} to the remote cache: {e}"),
+            }
+        });
+        // A closed queue means the store is shutting down.
+        let _ = self.writes.send(job);
+    }
+
+    /// Wait until every queued write has been sent.
+    pub(crate) async fn flush(&self) -> Result<(), StoreError> {
+        Self::submit(&self.writes, |_| Ok(())).await
+    }
+}
+
+/// An HTTP/1.1 connection, opened on first use and reopened as needed.
+struct Http {
+    /// Where to connect, `host:port`.
+    addr: String,
+    /// The `Host` header.
+    host: String,
+    /// How long to wait on the network once connected.
+    timeout: Duration,
+    /// Marked when the server can't be reached.
+    down: Arc<Down>,
+    stream: Option<BufReader<Watched>>,
+}
+
+/// A connection to the server that marks it down when reading or writing
+/// times out.
+struct Watched {
+    stream: TcpStream,
+    down: Arc<Down>,
+}
+
+impl Watched {
+    fn check<T>(&self, result: std::io::Result<T>) -> std::io::Result<T> {
+        use std::io::ErrorKind::{TimedOut, WouldBlock};
+        // Timeouts surface as `WouldBlock` on some platforms.
+        if let Err(e) = &result {
+            if matches!(e.kind(), TimedOut | WouldBlock) {
+                self.down.mark();
+            }
+        }
+        result
+    }
+}
+
+impl Read for Watched {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.stream.read(buf);
+        self.check(read)
+    }
+}
+
+impl Write for Watched {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.stream.write(buf);
+        self.check(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let flushed = self.stream.flush();
+        self.check(flushed)
+    }
+}
+
+impl Http {
+    fn new(addr: &str, host: &str, timeout: Duration, down: Arc<Down>) -> Self {
+        Http {
+            addr: addr.to_owned(),
+            host: host.to_owned(),
+            timeout,
+            down,
+            stream: None,
+        }
+    }
+
+    /// Send a request, returning the response's status and body.
+    ///
+    /// Failing to connect, or timing out, leaves the server alone for
+    /// [`COOL_DOWN`].
+    fn request(
+        &mut self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>), StoreError> {
+        // The server may have closed a kept-alive connection since it was
+        // last used, so a failure on one is retried once on a new one,
+        // unless it timed out.
+        if self.stream.is_some() {
+            match self.send(method, path, body) {
+                Ok(response) => return Ok(response),
+                Err(e) if self.down.is_down() => return Err(e),
+                Err(e) => log::debug!("retrying {method} {path} on a new connection: {e}"),
+            }
+        }
+        self.send(method, path, body)
+    }
+
+    /// Connect to the server, marking it down if it can't be reached.
+    fn connect(&self) -> Result<TcpStream, StoreError> {
+        net::connect(&*self.addr).map_err(|e| {
+            self.down.mark();
+            e.into()
+        })
+    }
+
+    fn send(
+        &mut self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<(u16, Vec<u8>), StoreError> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => {
+                let stream = self.connect()?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                BufReader::new(Watched {
+                    stream,
+                    down: self.down.clone(),
+                })
+            }
+        };
+        let mut stream = stream;
+        let writer = stream.get_mut();
+        write!(
+            writer,
+            "{method} {path} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
+            self.host,
+            body.len()
+        )?;
+        writer.write_all(body)?;
+        writer.flush()?;
+        let head = read_head(&mut stream)?.context(RemoteSnafu {
+            message: "server closed the connection",
+        })?;
+        let status = head
+            .start
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .context(RemoteSnafu {
+                message: format!("malformed status line {:?}", head.start),
+            })?;
+        let body = read_body(&mut stream, &head)?;
+        if !head
+            .header("connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+        {
+            self.stream = Some(stream);
+        }
+        Ok((status, body))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{net::CONNECT_TIMEOUT, Store, StoreOptions};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("potency-remote-test-{name}"));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn serve(name: &str) -> String {
+        let server = Server::bind(temp_dir(name), "127.0.0.1:0").unwrap();
+        let url = format!("http://{}/test", server.local_addr().unwrap());
+        std::thread::spawn(move || server.serve());
+        url
+    }
+
+    #[test]
+    fn keys_are_percent_encoded() {
+        assert_eq!(percent_encode("squares,12"), "squares%2C12");
+        assert_eq!(percent_encode("a b/é"), "a%20b%2F%C3%A9");
+        assert!(Remote::new("https://example.com", None).is_err());
+        assert!(Remote::new("http:///path", None).is_err());
+    }
+
+    #[test]
+    fn server_answers_get_and_put() {
+        let url = serve("http");
+        let addr = url["http://".len()..].trim_end_matches("/test");
+        let mut http = Http::new(addr, "localhost", TIMEOUT, Arc::default());
+        assert_eq!(http.request("GET", "/a", b"").unwrap(), (404, vec![]));
+        assert_eq!(http.request("PUT", "/a", b"one").unwrap().0, 204);
+        assert_eq!(
+            http.request("GET", "/a", b"").unwrap(),
+            (200, b"one".to_vec())
+        );
+        assert_eq!(http.request("DELETE", "/a", b"").unwrap().0, 405);
+        // All over the one connection.
+        assert!(http.stream.is_some());
+    }
+
+    #[test]
+    fn timeout_on_a_kept_alive_connection_marks_the_remote_down() {
+        // Answers the first request, then goes quiet.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            read_head(&mut reader).unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            std::thread::sleep(Duration::from_secs(5));
+        });
+        let timeout = Duration::from_millis(200);
+        let mut http = Http::new(&addr, "localhost", timeout, Arc::default());
+        assert_eq!(http.request("GET", "/a", b"").unwrap().0, 404);
+        let started = Instant::now();
+        assert!(http.request("GET", "/a", b"").is_err());
+        assert!(http.down.is_down());
+        // Not retried on a new connection.
+        assert!(started.elapsed() < timeout * 2);
+    }
+
+    #[test]
+    fn stores_share_results_through_the_remote() {
+        let url = serve("share");
+        let square = |store: &Store, x: u32| {
+            let store = store.namespace("squares");
+            async move {
+                store
+                    .entry(move |x: u32| Ok::<u32, crate::StoreError>(x * x))
+                    .param(x)
+                    .run()
+                    .await
+                    .unwrap()
+            }
+        };
+        smol::block_on(async {
+            let ci = Store::open_with(":memory:", StoreOptions::new().remote(&url))
+                .await
+                .unwrap();
+            assert_eq!(square(&ci, 12).await, 144);
+            ci.flush().await.unwrap();
+
+            let laptop = Store::open_with(":memory:", StoreOptions::new().remote(&url))
+                .await
+                .unwrap();
+            let hit = laptop
+                .namespace("squares")
+                .entry(|_: u32| -> Result<u32, crate::StoreError> { panic!("should be a hit") })
+                .param(12u32)
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(hit, 144);
+            // Written through to the local tier.
+            assert!(laptop.entry_info("squares,12").await.unwrap().is_some());
+
+            // An unreachable remote only costs the lookup.
+            let addr = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let offline = StoreOptions::new().remote(format!("http://{addr}"));
+            let offline = Store::open_with(":memory:", offline).await.unwrap();
+            assert_eq!(square(&offline, 3).await, 9);
+            offline.flush().await.unwrap();
+        });
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_stores_seal_what_they_send() {
+        let url = serve("sealed");
+        let open = |keyring: Option<crate::Keyring>| {
+            let mut options = StoreOptions::new().remote(&url);
+            if let Some(keyring) = keyring {
+                options = options.encryption(keyring);
+            }
+            Store::open_with(":memory:", options)
+        };
+        let secret = |store: &Store| {
+            let store = store.namespace("secret");
+            async move {
+                store
+                    .entry(|| Ok::<String, crate::StoreError>("hunter2".into()))
+                    .run()
+                    .await
+                    .unwrap()
+            }
+        };
+        smol::block_on(async {
+            let sender = open(Some(crate::Keyring::new("a", [1; 32]))).await.unwrap();
+            secret(&sender).await;
+            sender.flush().await.unwrap();
+            for file in
+                std::fs::read_dir(std::env::temp_dir().join("potency-remote-test-sealed")).unwrap()
+            {
+                let stored = std::fs::read(file.unwrap().path()).unwrap();
+                assert!(!String::from_utf8_lossy(&stored).contains("hunter2"));
+            }
+
+            let receiver = open(Some(crate::Keyring::new("a", [1; 32]))).await.unwrap();
+            let hit = receiver
+                .namespace("secret")
+                .entry(|| -> Result<String, crate::StoreError> { panic!("should be a hit") })
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(hit, "hunter2");
+
+            // Without the key, the sealed entry is a miss.
+            let outsider = open(None).await.unwrap();
+            assert_eq!(secret(&outsider).await, "hunter2");
+            assert!(outsider.entry_info("secret").await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn unroutable_remote_is_skipped_after_a_lookup() {
+        let square = |store: &Store, x: u32| {
+            let store = store.namespace("squares");
+            async move {
+                store
+                    .entry(move |x: u32| Ok::<u32, crate::StoreError>(x * x))
+                    .param(x)
+                    .run()
+                    .await
+                    .unwrap()
+            }
+        };
+        smol::block_on(async {
+            // TEST-NET-1 is never routed, so connecting can only time out
+            // or fail.
+            let options = StoreOptions::new().remote("http://192.0.2.1:7879");
+            let store = Store::open_with(":memory:", options).await.unwrap();
+            let started = Instant::now();
+            assert_eq!(square(&store, 3).await, 9);
+            assert!(started.elapsed() < CONNECT_TIMEOUT + Duration::from_secs(1));
+
+            // Cooling down: neither lookups nor sends touch the network.
+            let started = Instant::now();
+            for x in 4..8 {
+                assert_eq!(square(&store, x).await, x * x);
+            }
+            assert!(started.elapsed() < Duration::from_millis(500));
+            store.flush().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn lookups_do_not_wait_behind_writes() {
+        // Misses at once, but takes its time over every write.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut writer = stream;
+                    while let Some(head) = read_head(&mut reader).unwrap() {
+                        read_body(&mut reader, &head).unwrap();
+                        if head.start.starts_with("PUT") {
+                            std::thread::sleep(Duration::from_millis(300));
+                        }
+                        write_response(&mut writer, 404, "Not Found", b"").unwrap();
+                    }
+                });
+            }
+        });
+        smol::block_on(async {
+            let store = Store::open_with(":memory:", StoreOptions::new().remote(url))
+                .await
+                .unwrap();
+            let started = Instant::now();
+            for x in 0..4u32 {
+                let n = store
+                    .entry(|x: u32| Ok::<u32, crate::StoreError>(x + 1))
+                    .param(x)
+                    .run()
+                    .await
+                    .unwrap();
+                assert_eq!(n, x + 1);
+            }
+            // Waiting behind the writes would take at least 900ms.
+            assert!(started.elapsed() < Duration::from_millis(600));
+            store.flush().await.unwrap();
+            assert!(started.elapsed() >= Duration::from_millis(4 * 300));
+        });
+    }
+}
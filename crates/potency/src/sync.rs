@@ -0,0 +1,738 @@
+//! Sharing results between stores over TCP.
+//!
+//! A [`Server`] exposes a store on a socket. A [`Client`] connected to it
+//! pulls the server's changes into a local store, or pushes the local
+//! store's changes to it, using the changelog behind
+//! [`Store::changes_since`]. Each side remembers how far it got by the
+//! sequence numbers the calls return, so only new changes cross the wire
+//! next time.
+//!
+//! ```rust,no_run
+//! # async fn doc(local: potency::Store, remote: potency::Store) -> Result<(), potency::StoreError> {
+//! use potency::sync::{Client, Server};
+//!
+//! // On one worker:
+//! let server = Server::bind(remote, "0.0.0.0:7878")?;
+//! std::thread::spawn(move || server.serve());
+//!
+//! // On another:
+//! let client = Client::connect("10.0.0.2:7878").await?;
+//! let pulled = client.pull(&local, 0).await?;
+//! let pushed = client.push(&local, 0).await?;
+//! // Next time, only what changed since:
+//! client.pull(&local, pulled.seq).await?;
+//! client.push(&local, pushed.seq).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! ## Protocol
+//!
+//! Every message is a frame: a big-endian `u32` length, then at most a few
+//! MiB of JSON. The client opens with a `hello` carrying
+//! [`PROTOCOL_VERSION`], which the server echoes or refuses with an
+//! `error`. Entries travel as the JSON Lines of [`Store::export`], split
+//! over as many `changes` frames as they take and followed by an end
+//! marker: a `pull` is answered with `changes` frames and then `pulled`,
+//! and a push is `changes` frames and then `push`, answered with `pushed`.
+//! Frames are filled as each page of changes is read and split lines
+//! wherever they fill up, so an entry too large for one frame spans
+//! several; the receiving end joins the pieces back into lines.
+//!
+//! A server serves a fixed number of connections at once, and both ends
+//! give up on a peer that leaves them waiting for half a minute, so a
+//! client left idle that long must connect again. There is no
+//! authentication or encryption: serve on a trusted network only.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc},
+};
+
+use snafu::OptionExt;
+
+use crate::{
+    net::{self, Slots, TIMEOUT},
+    ConflictPolicy, ImportReport, Store, StoreError, SyncSnafu,
+};
+
+/// The version of the framing and messages, sent in every handshake.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Frames longer than this are refused rather than buffered.
+const MAX_FRAME: u32 = 4 << 20;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Hello {
+        version: u32,
+    },
+    /// Ask for the server's changes after `since`.
+    Pull {
+        since: u64,
+    },
+    /// Some of the entries pulled or pushed, as JSON Lines. The last line
+    /// may be cut short and go on in the next `changes` frame.
+    Changes {
+        lines: String,
+    },
+    /// The end of the reply to `pull`, whose entries went up to change
+    /// `seq`.
+    Pulled {
+        seq: u64,
+    },
+    /// The end of the entries pushed, for the server to reply to.
+    Push,
+    /// The reply to `push`.
+    Pushed {
+        report: ImportReport,
+    },
+    Error {
+        message: String,
+    },
+}
+
+fn write_frame(stream: &mut impl Write, message: &Message) -> Result<(), StoreError> {
+    let bytes = serde_json::to_vec(message)?;
+    let len = u32::try_from(bytes.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME)
+        .context(SyncSnafu {
+            message: "message too large",
+        })?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.write_all(&bytes)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Read a frame, or `None` if the peer closed the connection between
+/// frames.
+fn read_frame(stream: &mut impl Read) -> Result<Option<Message>, StoreError> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME {
+        return SyncSnafu {
+            message: format!("peer sent a {len} byte frame"),
+        }
+        .fail();
+    }
+    let mut bytes = vec![0; len as usize];
+    stream.read_exact(&mut bytes)?;
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+/// Packs JSON Lines into `changes` messages whose frames are each at most
+/// `max_frame` bytes long, splitting lines where a frame fills up.
+struct Frames {
+    /// How long the lines of a frame may be once escaped as a JSON string.
+    budget: usize,
+    /// The lines of the frame being filled.
+    text: Vec<u8>,
+    /// Their escaped length, or more.
+    size: usize,
+    filled: Vec<Message>,
+}
+
+impl Frames {
+    fn new(max_frame: usize) -> Self {
+        /// Room for the rest of a `changes` message around its lines.
+        const ENVELOPE: usize = 64;
+
+        Frames {
+            budget: max_frame.saturating_sub(ENVELOPE),
+            text: Vec::new(),
+            size: 0,
+            filled: Vec::new(),
+        }
+    }
+
+    /// Add the JSON Lines `bytes`, filling frames as needed.
+    fn push(&mut self, bytes: &[u8]) -> Result<(), StoreError> {
+        /// The most a character escapes to: `\u001f`.
+        const MAX_ESCAPED: usize = 6;
+
+        for &byte in bytes {
+            // Frames are cut before a character that might not fit, never
+            // inside one.
+            let starts_char = byte & 0xc0 != 0x80;
+            if starts_char && self.size + MAX_ESCAPED > self.budget {
+                self.finish()?;
+            }
+            self.size += match byte {
+                b'"' | b'\\' => 2,
+                ..0x20 => MAX_ESCAPED,
+                _ => 1,
+            };
+            self.text.push(byte);
+        }
+        Ok(())
+    }
+
+    /// End the frame being filled, if it has anything in it.
+    fn finish(&mut self) -> Result<(), StoreError> {
+        if self.text.is_empty() {
+            return Ok(());
+        }
+        let lines = String::from_utf8(std::mem::take(&mut self.text)).map_err(|e| {
+            SyncSnafu {
+                message: format!("changes are not UTF-8: {e}"),
+            }
+            .build()
+        })?;
+        self.size = 0;
+        self.filled.push(Message::Changes { lines });
+        Ok(())
+    }
+
+    /// The frames filled so far.
+    fn take(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.filled)
+    }
+}
+
+/// A store's changes after a change number, framed a page at a time.
+struct Outgoing<'a> {
+    store: &'a Store,
+    /// The number of the last change framed.
+    seq: u64,
+    more: bool,
+    frames: Frames,
+}
+
+impl<'a> Outgoing<'a> {
+    fn new(store: &'a Store, since: u64) -> Self {
+        Outgoing {
+            store,
+            seq: since,
+            more: true,
+            frames: Frames::new(MAX_FRAME as usize),
+        }
+    }
+
+    /// The frames filled by the next page of changes, the last of them
+    /// ending with whatever is left, or `None` after the last page.
+    async fn next(&mut self) -> Result<Option<Vec<Message>>, StoreError> {
+        if !self.more {
+            return Ok(None);
+        }
+        let mut page = Vec::new();
+        (self.seq, self.more) = self.store.changes_page(self.seq, &mut page).await?;
+        self.frames.push(&page)?;
+        if !self.more {
+            self.frames.finish()?;
+        }
+        Ok(Some(self.frames.take()))
+    }
+}
+
+/// Imports the `changes` frames received, joining lines split between
+/// them.
+#[derive(Default)]
+struct Incoming {
+    /// The start of a line whose end is in a later frame.
+    partial: String,
+    report: ImportReport,
+}
+
+impl Incoming {
+    /// Import the whole lines received so far with those in `lines`.
+    async fn import(
+        &mut self,
+        store: &Store,
+        lines: &str,
+        policy: ConflictPolicy,
+    ) -> Result<(), StoreError> {
+        self.partial.push_str(lines);
+        let Some(end) = self.partial.rfind('\n') else {
+            return Ok(());
+        };
+        let rest = self.partial.split_off(end + 1);
+        let whole = std::mem::replace(&mut self.partial, rest);
+        self.report
+            .add(store.import(whole.as_bytes(), policy).await?);
+        Ok(())
+    }
+
+    /// Import whatever is left, once every frame is received.
+    async fn finish(
+        mut self,
+        store: &Store,
+        policy: ConflictPolicy,
+    ) -> Result<ImportReport, StoreError> {
+        let rest = std::mem::take(&mut self.partial);
+        self.report
+            .add(store.import(rest.as_bytes(), policy).await?);
+        Ok(self.report)
+    }
+}
+
+/// Serves a store to [`Client`]s.
+pub struct Server {
+    store: Store,
+    listener: TcpListener,
+    policy: ConflictPolicy,
+}
+
+impl Server {
+    /// Listen on `addr` for clients of `store`. Bind to port 0 to have the
+    /// system pick a free port, then read it from [`Server::local_addr`].
+    pub fn bind(store: Store, addr: impl ToSocketAddrs) -> Result<Self, StoreError> {
+        Ok(Server {
+            store,
+            listener: TcpListener::bind(addr)?,
+            policy: ConflictPolicy::LastWriterWins,
+        })
+    }
+
+    /// How pushed entries are imported. Defaults to
+    /// [`ConflictPolicy::LastWriterWins`].
+    pub fn policy(mut self, policy: ConflictPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr, StoreError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept clients until the listener fails, each on its own thread,
+    /// up to a fixed number at once.
+    ///
+    /// Blocks the calling thread, so start it on one of its own.
+    pub fn serve(self) -> Result<(), StoreError> {
+        let slots = Arc::new(Slots::default());
+        loop {
+            let slot = Slots::acquire(&slots);
+            let (stream, _) = self.listener.accept()?;
+            let (store, policy) = (self.store.clone(), self.policy);
+            std::thread::Builder::new()
+                .name("potency-sync".into())
+                .spawn(move || {
+                    let _slot = slot;
+                    let peer = stream.peer_addr().ok();
+                    if let Err(e) = serve_client(&store, policy, stream) {
+                        log::warn!("sync with {peer:?} failed: {e}");
+                    }
+                })?;
+        }
+    }
+}
+
+fn serve_client(
+    store: &Store,
+    policy: ConflictPolicy,
+    mut stream: TcpStream,
+) -> Result<(), StoreError> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    match read_frame(&mut stream)? {
+        Some(Message::Hello {
+            version: PROTOCOL_VERSION,
+        }) => write_frame(
+            &mut stream,
+            &Message::Hello {
+                version: PROTOCOL_VERSION,
+            },
+        )?,
+        Some(Message::Hello { version }) => {
+            let message = format!("protocol version {version} is not {PROTOCOL_VERSION}");
+            return write_frame(&mut stream, &Message::Error { message });
+        }
+        _ => {
+            let message = "expected hello".to_owned();
+            return write_frame(&mut stream, &Message::Error { message });
+        }
+    }
+    let error = |e: StoreError| Message::Error {
+        message: e.to_string(),
+    };
+    // The push being received: what its frames so far imported, or why
+    // one of them failed.
+    let mut pushed = Ok(Incoming::default());
+    while let Some(request) = read_frame(&mut stream)? {
+        // The store's futures only wait on its database threads, so
+        // blocking this connection's thread on them is fine.
+        use futures_lite::future::block_on;
+        match request {
+            Message::Pull { since } => {
+                let mut outgoing = Outgoing::new(store, since);
+                loop {
+                    match block_on(outgoing.next()) {
+                        Ok(Some(frames)) => {
+                            for frame in &frames {
+                                write_frame(&mut stream, frame)?;
+                            }
+                        }
+                        Ok(None) => {
+                            let seq = outgoing.seq;
+                            write_frame(&mut stream, &Message::Pulled { seq })?;
+                            break;
+                        }
+                        Err(e) => {
+                            write_frame(&mut stream, &error(e))?;
+                            break;
+                        }
+                    }
+                }
+            }
+            Message::Changes { lines } => {
+                // Each part is imported as it arrives, so a push is never
+                // held in memory whole.
+                if let Ok(incoming) = &mut pushed {
+                    if let Err(e) = block_on(incoming.import(store, &lines, policy)) {
+                        pushed = Err(e);
+                    }
+                }
+            }
+            Message::Push => {
+                let report = std::mem::replace(&mut pushed, Ok(Incoming::default()))
+                    .and_then(|incoming| block_on(incoming.finish(store, policy)));
+                let reply = match report {
+                    Ok(report) => Message::Pushed { report },
+                    Err(e) => error(e),
+                };
+                write_frame(&mut stream, &reply)?;
+            }
+            request => {
+                let e = SyncSnafu {
+                    message: format!("unexpected request {request:?}"),
+                }
+                .build();
+                write_frame(&mut stream, &error(e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What [`Client::pull`] or [`Client::push`] did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Synced {
+    /// How the receiving store imported the changes.
+    pub report: ImportReport,
+    /// The sending store's change number to sync from next time.
+    pub seq: u64,
+}
+
+/// Where the frames of a request come from, as they are ready, and where
+/// to send the frames of the reply.
+type Request = (
+    async_channel::Receiver<Message>,
+    async_channel::Sender<Result<Message, StoreError>>,
+);
+
+type Replies = async_channel::Receiver<Result<Message, StoreError>>;
+
+/// A connection to a [`Server`].
+///
+/// Network reads and writes run on a thread of the client's own, so they
+/// never block the caller's executor. The connection closes when the
+/// client is dropped.
+pub struct Client {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Client {
+    /// Connect to the server at `addr` and agree on the protocol version.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, StoreError> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        let (requests, queue) = mpsc::channel::<Request>();
+        let (ready, connected) = async_channel::bounded::<Result<(), StoreError>>(1);
+        std::thread::Builder::new()
+            .name("potency-sync-client".into())
+            .spawn(move || {
+                let connected = net::connect(&addrs[..]).and_then(|stream| {
+                    stream.set_read_timeout(Some(TIMEOUT))?;
+                    stream.set_write_timeout(Some(TIMEOUT))?;
+                    Ok(stream)
+                });
+                let mut stream = match connected {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let _ = ready.send_blocking(Err(e.into()));
+                        return;
+                    }
+                };
+                let _ = ready.send_blocking(Ok(()));
+                for (frames, replies) in queue {
+                    // Whether the request ended with a frame the server
+                    // answers, if any of it was sent.
+                    let mut ended = None;
+                    while let Ok(frame) = frames.recv_blocking() {
+                        if let Err(e) = write_frame(&mut stream, &frame) {
+                            // The connection is broken mid-frame.
+                            let _ = replies.send_blocking(Err(e));
+                            return;
+                        }
+                        ended = Some(!matches!(frame, Message::Changes { .. }));
+                    }
+                    match ended {
+                        None => continue,
+                        // The caller gave up on a push: end it, so the
+                        // next request starts afresh.
+                        Some(false) => {
+                            if let Err(e) = write_frame(&mut stream, &Message::Push) {
+                                let _ = replies.send_blocking(Err(e));
+                                return;
+                            }
+                        }
+                        Some(true) => {}
+                    }
+                    // Read the whole reply even if the caller stopped
+                    // listening, so the next one starts at a frame.
+                    loop {
+                        let reply = read_frame(&mut stream).and_then(|reply| {
+                            reply.context(SyncSnafu {
+                                message: "server closed the connection",
+                            })
+                        });
+                        let more = matches!(reply, Ok(Message::Changes { .. }));
+                        let _ = replies.send_blocking(reply);
+                        if !more {
+                            break;
+                        }
+                    }
+                }
+            })?;
+        connected.recv().await.ok().context(SyncSnafu {
+            message: "connection thread stopped",
+        })??;
+        let client = Client { requests };
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION,
+        };
+        let replies = client.request(hello).await?;
+        match reply(&replies).await? {
+            Message::Hello { .. } => Ok(client),
+            reply => Err(unexpected(reply)),
+        }
+    }
+
+    /// Start a request, returning where to send its frames and the frames
+    /// of the reply, which come once the sender is dropped.
+    fn start(&self) -> Result<(async_channel::Sender<Message>, Replies), StoreError> {
+        let (frames, queued) = async_channel::bounded(1);
+        // Bounded, so the connection thread reads no further ahead than
+        // the caller imports.
+        let (replies, receiver) = async_channel::bounded(1);
+        self.requests
+            .send((queued, replies))
+            .ok()
+            .context(SyncSnafu {
+                message: "connection thread stopped",
+            })?;
+        Ok((frames, receiver))
+    }
+
+    /// Send the one frame `message`, returning the frames of the reply.
+    async fn request(&self, message: Message) -> Result<Replies, StoreError> {
+        let (frames, replies) = self.start()?;
+        send(&frames, message).await?;
+        Ok(replies)
+    }
+
+    /// Import the server's changes after its change number `since` into
+    /// `store`, keeping whichever entry was written last.
+    pub async fn pull(&self, store: &Store, since: u64) -> Result<Synced, StoreError> {
+        let replies = self.request(Message::Pull { since }).await?;
+        let mut incoming = Incoming::default();
+        let policy = ConflictPolicy::LastWriterWins;
+        loop {
+            match reply(&replies).await? {
+                Message::Changes { lines } => incoming.import(store, &lines, policy).await?,
+                Message::Pulled { seq } => {
+                    let report = incoming.finish(store, policy).await?;
+                    return Ok(Synced { report, seq });
+                }
+                reply => return Err(unexpected(reply)),
+            }
+        }
+    }
+
+    /// Send `store`'s changes after its change number `since` to the
+    /// server, which imports them by its [`Server::policy`].
+    pub async fn push(&self, store: &Store, since: u64) -> Result<Synced, StoreError> {
+        let (frames, replies) = self.start()?;
+        let mut outgoing = Outgoing::new(store, since);
+        while let Some(page) = outgoing.next().await? {
+            for frame in page {
+                send(&frames, frame).await?;
+            }
+        }
+        send(&frames, Message::Push).await?;
+        drop(frames);
+        match reply(&replies).await? {
+            Message::Pushed { report } => Ok(Synced {
+                report,
+                seq: outgoing.seq,
+            }),
+            reply => Err(unexpected(reply)),
+        }
+    }
+}
+
+/// Hand `frame` to the connection thread.
+async fn send(frames: &async_channel::Sender<Message>, frame: Message) -> Result<(), StoreError> {
+    frames.send(frame).await.ok().context(SyncSnafu {
+        message: "connection thread stopped",
+    })
+}
+
+/// The next frame of a reply.
+async fn reply(replies: &Replies) -> Result<Message, StoreError> {
+    replies.recv().await.ok().context(SyncSnafu {
+        message: "connection thread stopped",
+    })?
+}
+
+fn unexpected(reply: Message) -> StoreError {
+    let message = match reply {
+        Message::Error { message } => format!("server error: {message}"),
+        reply => format!("unexpected reply {reply:?}"),
+    };
+    SyncSnafu { message }.build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip_and_refuse_oversize() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &Message::Pull { since: 7 }).unwrap();
+        assert_eq!(&wire[..4], &(wire.len() as u32 - 4).to_be_bytes());
+        let mut reader = wire.as_slice();
+        assert!(matches!(
+            read_frame(&mut reader).unwrap(),
+            Some(Message::Pull { since: 7 })
+        ));
+        assert!(read_frame(&mut reader).unwrap().is_none());
+
+        let oversize = (MAX_FRAME + 1).to_be_bytes();
+        assert!(matches!(
+            read_frame(&mut oversize.as_slice()),
+            Err(StoreError::Sync { .. })
+        ));
+    }
+
+    #[test]
+    fn changes_are_split_into_frames() {
+        let frames = |lines: &str, max_frame: usize| {
+            let mut frames = Frames::new(max_frame);
+            frames.push(lines.as_bytes()).unwrap();
+            frames.finish().unwrap();
+            let mut joined = String::new();
+            let messages = frames.take();
+            for message in &messages {
+                let mut wire = Vec::new();
+                write_frame(&mut wire, message).unwrap();
+                assert!(wire.len() - 4 <= max_frame);
+                let Message::Changes { lines } = message else {
+                    panic!("expected changes, got {message:?}");
+                };
+                joined.push_str(lines);
+            }
+            assert_eq!(joined, lines);
+            messages.len()
+        };
+        let lines = ["{\"k\":\"a\"}\n", "{\"k\":\"bb\"}\n", "{\"k\":\"ccc\"}\n"].concat();
+        assert_eq!(frames(&lines, MAX_FRAME as usize), 1);
+        assert_eq!(frames("", MAX_FRAME as usize), 0);
+        // Lines longer than a frame, and characters that don't fit whole.
+        assert!(frames(&lines, 80) > 3);
+        let wide = format!("{{\"k\":\"{}\"}}\n", "é\\".repeat(100));
+        assert!(frames(&wide, 80) > 1);
+
+        let mut frames = Frames::new(80);
+        frames.push(&[b'{', 0xff, b'}']).unwrap();
+        assert!(matches!(frames.finish(), Err(StoreError::Sync { .. })));
+    }
+
+    #[test]
+    fn peers_sync_over_localhost() {
+        let query = |store: &Store, x: u32| {
+            let store = store.namespace("squares");
+            async move {
+                store
+                    .entry(|x: u32| Ok::<u32, StoreError>(x * x))
+                    .param(x)
+                    .run()
+                    .await
+                    .unwrap()
+            }
+        };
+        smol::block_on(async {
+            let remote = Store::in_memory().await.unwrap();
+            query(&remote, 2).await;
+            let server = Server::bind(remote.clone(), "127.0.0.1:0").unwrap();
+            let addr = server.local_addr().unwrap();
+            std::thread::spawn(move || server.serve());
+
+            let local = Store::in_memory().await.unwrap();
+            query(&local, 3).await;
+            let client = Client::connect(addr).await.unwrap();
+            let pulled = client.pull(&local, 0).await.unwrap();
+            assert_eq!(pulled.report.imported, 1);
+            let pushed = client.push(&local, 0).await.unwrap();
+            assert_eq!(pushed.report.imported, 1);
+            // The pulled entry went back too, and lost the tie.
+            assert_eq!(pushed.report.skipped, 1);
+
+            for store in [&local, &remote] {
+                for x in [2, 3] {
+                    assert!(store
+                        .entry_info(format!("squares,{x}"))
+                        .await
+                        .unwrap()
+                        .is_some());
+                }
+            }
+
+            // Nothing new either way.
+            let again = client.pull(&local, pulled.seq).await.unwrap();
+            assert_eq!(again.report.imported, 0);
+            query(&local, 4).await;
+            let again = client.push(&local, pushed.seq).await.unwrap();
+            assert_eq!(again.report.imported, 1);
+            assert!(again.seq > pushed.seq);
+
+            // An entry larger than a frame goes over in pieces.
+            let big = || Ok::<_, StoreError>("x".repeat(MAX_FRAME as usize + 1));
+            remote.namespace("big").entry(big).run().await.unwrap();
+            let again = client.pull(&local, pulled.seq).await.unwrap();
+            assert_eq!(again.report.imported, 1);
+            let value = local
+                .namespace("big")
+                .entry(|| -> Result<String, StoreError> { panic!("should be pulled") })
+                .run()
+                .await
+                .unwrap();
+            assert_eq!(value.len(), MAX_FRAME as usize + 1);
+        });
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        smol::block_on(async {
+            let server = Server::bind(Store::in_memory().await.unwrap(), "127.0.0.1:0").unwrap();
+            let addr = server.local_addr().unwrap();
+            std::thread::spawn(move || server.serve());
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write_frame(&mut stream, &Message::Hello { version: 0 }).unwrap();
+            assert!(matches!(
+                read_frame(&mut stream).unwrap(),
+                Some(Message::Error { .. })
+            ));
+        });
+    }
+}